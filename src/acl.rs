@@ -0,0 +1,224 @@
+use std::{collections::HashMap, error::Error as StdError, fs, path::Path};
+
+use serde::{de::Unexpected, Deserialize, Deserializer};
+use url::Url;
+
+use crate::{auth::Identity, policy::BrokerPolicy, Error};
+
+#[derive(Clone, Copy, Debug)]
+pub enum Access {
+    Publish,
+    Subscribe,
+}
+
+// Topic filters and brokers each identity may use. Everything is allowed when no ACL file is
+// configured, and nothing is allowed to identities it doesn't list.
+#[derive(Deserialize, Default)]
+pub struct Acl {
+    identities: Option<HashMap<String, Permissions>>,
+}
+
+#[derive(Deserialize)]
+struct Permissions {
+    #[serde(default, deserialize_with = "deserialize_filters")]
+    publish: Vec<String>,
+    #[serde(default, deserialize_with = "deserialize_filters")]
+    subscribe: Vec<String>,
+    // Any broker when empty.
+    #[serde(default, deserialize_with = "BrokerPolicy::deserialize_allowed")]
+    brokers: BrokerPolicy,
+}
+
+impl Acl {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn StdError + Send + Sync>> {
+        let mut acl: Self = toml::from_str(&fs::read_to_string(path)?)?;
+        acl.identities.get_or_insert_with(HashMap::new);
+        Ok(acl)
+    }
+
+    // Topics are checked before the broker so that a denied request names the offending topic,
+    // and before any connection is made.
+    pub async fn authorize<'a>(
+        &self,
+        identity: &Identity,
+        broker: &Url,
+        topics: impl IntoIterator<Item = &'a str>,
+        access: Access,
+    ) -> Result<(), Error> {
+        let identities = match &self.identities {
+            Some(identities) => identities,
+            None => return Ok(()),
+        };
+        let permissions = match identity {
+            Identity::User(name) => identities.get(name),
+            Identity::Anonymous => None,
+        };
+        let filters = match (permissions, access) {
+            (Some(permissions), Access::Publish) => permissions.publish.as_slice(),
+            (Some(permissions), Access::Subscribe) => permissions.subscribe.as_slice(),
+            (None, _) => &[],
+        };
+        for topic in topics {
+            if !filters.iter().any(|filter| filter_covers(filter, topic)) {
+                return Err(Error::TopicForbidden(topic.to_owned()));
+            }
+        }
+        match permissions {
+            Some(permissions) => permissions.brokers.check(broker).await.map(drop),
+            None => Err(Error::BrokerForbidden),
+        }
+    }
+}
+
+// Whether every topic matched by `topic`, a topic name or filter, is also matched by `filter`.
+fn filter_covers(filter: &str, topic: &str) -> bool {
+    // Wildcards starting a filter don't match system topics.
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic_levels.next()) {
+            // Also matches the parent level, `a/#` matching `a`.
+            ("#", _) => return true,
+            (_, None) | ("+", Some("#")) => return false,
+            ("+", Some(_)) => {}
+            (level, Some(topic_level)) if level == topic_level => {}
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+fn is_valid_filter(filter: &str) -> bool {
+    let levels = filter.split('/').collect::<Vec<_>>();
+    !filter.is_empty()
+        && levels.iter().enumerate().all(|(index, level)| {
+            (*level == "#" && index == levels.len() - 1)
+                || *level == "+"
+                || !level.contains(['#', '+'])
+        })
+}
+
+fn deserialize_filters<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let filters = Vec::<String>::deserialize(deserializer)?;
+    match filters.iter().find(|filter| !is_valid_filter(filter)) {
+        Some(filter) => Err(serde::de::Error::invalid_value(
+            Unexpected::Str(filter),
+            &"MQTT topic filter",
+        )),
+        None => Ok(filters),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::{filter_covers, is_valid_filter, Access, Acl};
+    use crate::{auth::Identity, Error};
+
+    fn acl() -> Acl {
+        toml::from_str(
+            r#"
+            [identities.alice]
+            publish = ["teams/alice/#"]
+            subscribe = ["teams/alice/#", "teams/+/status"]
+            brokers = ["mqtts://*.example.com"]
+
+            [identities.bob]
+            publish = ["teams/bob/+/cmd"]
+            "#,
+        )
+        .unwrap()
+    }
+
+    async fn authorize(user: &str, broker: &str, topic: &str, access: Access) -> Result<(), Error> {
+        let broker: Url = broker.parse().unwrap();
+        acl()
+            .authorize(&Identity::User(user.to_owned()), &broker, [topic], access)
+            .await
+    }
+
+    #[test]
+    fn filters() {
+        assert!(filter_covers("a/#", "a"));
+        assert!(filter_covers("a/#", "a/b/c"));
+        assert!(filter_covers("a/#", "a/+/c"));
+        assert!(filter_covers("a/+/c", "a/b/c"));
+        assert!(filter_covers("a/+/c", "a/+/c"));
+        assert!(!filter_covers("a/+/c", "a/#"));
+        assert!(!filter_covers("a/+", "a/b/c"));
+        assert!(!filter_covers("a/b", "a/+"));
+        assert!(!filter_covers("#", "$SYS/uptime"));
+        assert!(filter_covers("$SYS/#", "$SYS/uptime"));
+
+        assert!(is_valid_filter("a/+/#"));
+        assert!(!is_valid_filter("a/#/b"));
+        assert!(!is_valid_filter("a/b+"));
+        assert!(!is_valid_filter(""));
+    }
+
+    #[tokio::test]
+    async fn disabled() {
+        let broker = "tcp://broker.com".parse().unwrap();
+        assert!(Acl::default()
+            .authorize(&Identity::Anonymous, &broker, ["a"], Access::Publish)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn topics() {
+        let broker = "mqtts://eu.example.com";
+        assert!(
+            authorize("alice", broker, "teams/alice/door", Access::Publish)
+                .await
+                .is_ok()
+        );
+        assert!(
+            authorize("alice", broker, "teams/bob/status", Access::Subscribe)
+                .await
+                .is_ok()
+        );
+        assert!(matches!(
+            authorize("alice", broker, "teams/bob/door", Access::Publish).await,
+            Err(Error::TopicForbidden(topic)) if topic == "teams/bob/door"
+        ));
+        assert!(matches!(
+            authorize("alice", broker, "teams/#", Access::Subscribe).await,
+            Err(Error::TopicForbidden(_))
+        ));
+        assert!(matches!(
+            authorize("carol", broker, "teams/alice/door", Access::Publish).await,
+            Err(Error::TopicForbidden(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn brokers() {
+        assert!(matches!(
+            authorize(
+                "alice",
+                "tcp://10.0.0.1",
+                "teams/alice/door",
+                Access::Publish
+            )
+            .await,
+            Err(Error::BrokerForbidden)
+        ));
+        assert!(
+            authorize("bob", "tcp://10.0.0.1", "teams/bob/1/cmd", Access::Publish)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn invalid_filter() {
+        assert!(toml::from_str::<Acl>("[identities.alice]\npublish = [\"a/#/b\"]").is_err());
+    }
+}
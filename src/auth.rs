@@ -0,0 +1,334 @@
+use std::{error::Error as StdError, fmt, fs, path::Path, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, State},
+    http::{header, request::Parts, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use log::info;
+use serde::Deserialize;
+
+use crate::{
+    config::Config,
+    misc::{constant_time_eq, header_str, query_param},
+    Error,
+};
+
+// The authenticated caller, available to handlers as an extractor.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Identity {
+    // Authentication is disabled.
+    Anonymous,
+    // The name of an API key, or the subject of a JWT.
+    User(String),
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Anonymous => write!(f, "-"),
+            Self::User(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Identity {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<Self>()
+            .cloned()
+            .unwrap_or(Self::Anonymous))
+    }
+}
+
+#[derive(Default)]
+pub struct Authenticator {
+    // Name and key pairs.
+    api_keys: Vec<(String, String)>,
+    jwt: Option<JwtVerifier>,
+}
+
+impl Authenticator {
+    // API keys are given as comma separated `name:key` pairs.
+    pub fn new(
+        api_keys: &str,
+        jwt: Option<JwtVerifier>,
+    ) -> Result<Self, Box<dyn StdError + Send + Sync>> {
+        Ok(Self {
+            api_keys: api_keys
+                .split(',')
+                .map(str::trim)
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| match pair.split_once(':') {
+                    Some((name, key)) if !name.is_empty() && !key.is_empty() => {
+                        Ok((name.to_owned(), key.to_owned()))
+                    }
+                    _ => Err("API keys must be name:key pairs"),
+                })
+                .collect::<Result<_, _>>()?,
+            jwt,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.api_keys.is_empty() || self.jwt.is_some()
+    }
+
+    // Headers take precedence over query parameters, used by clients unable to set headers
+    // (browser WebSockets). Bearer tokens can either be API keys or JWTs.
+    fn authenticate(&self, parts: &Parts) -> Result<Identity, Error> {
+        if !self.is_enabled() {
+            return Ok(Identity::Anonymous);
+        }
+        if let Some(key) = header_str(&parts.headers, "X-Api-Key")
+            .map(str::to_owned)
+            .or_else(|| query_param(&parts.uri, "apiKey"))
+        {
+            return self.api_key(&key).ok_or(Error::Unauthorized);
+        }
+        let token = header_str(&parts.headers, header::AUTHORIZATION)
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_owned)
+            .or_else(|| query_param(&parts.uri, "accessToken"))
+            .ok_or(Error::Unauthorized)?;
+        self.api_key(&token)
+            .or_else(|| self.jwt.as_ref()?.verify(&token))
+            .ok_or(Error::Unauthorized)
+    }
+
+    fn api_key(&self, key: &str) -> Option<Identity> {
+        self.api_keys
+            .iter()
+            .find(|(_, expected)| constant_time_eq(key, expected))
+            .map(|(name, _)| Identity::User(name.clone()))
+    }
+}
+
+pub struct JwtVerifier {
+    keys: Vec<JwtKey>,
+    validation: Validation,
+}
+
+struct JwtKey {
+    id: Option<String>,
+    // Any algorithm of the key's family when unspecified.
+    algorithm: Option<Algorithm>,
+    key: DecodingKey,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+impl JwtVerifier {
+    // Keys are read from a JWKS file, or a PEM encoded RSA, EC or Ed25519 public key.
+    pub fn load(
+        path: &Path,
+        issuer: Option<&str>,
+        audience: Option<&str>,
+    ) -> Result<Self, Box<dyn StdError + Send + Sync>> {
+        let content = fs::read(path)?;
+        let keys = if content.iter().find(|byte| !byte.is_ascii_whitespace()) == Some(&b'{') {
+            serde_json::from_slice::<JwkSet>(&content)?
+                .keys
+                .iter()
+                .map(|jwk| {
+                    Ok(JwtKey {
+                        id: jwk.common.key_id.clone(),
+                        algorithm: jwk.common.algorithm,
+                        key: DecodingKey::from_jwk(jwk)?,
+                    })
+                })
+                .collect::<Result<Vec<_>, jsonwebtoken::errors::Error>>()?
+        } else {
+            let key = DecodingKey::from_rsa_pem(&content)
+                .or_else(|_| DecodingKey::from_ec_pem(&content))
+                .or_else(|_| DecodingKey::from_ed_pem(&content))
+                .map_err(|_| "unsupported JWT key format")?;
+            vec![JwtKey {
+                id: None,
+                algorithm: None,
+                key,
+            }]
+        };
+        Ok(Self::new(keys, issuer, audience))
+    }
+
+    fn new(keys: Vec<JwtKey>, issuer: Option<&str>, audience: Option<&str>) -> Self {
+        let mut validation = Validation::default();
+        if let Some(issuer) = issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        if let Some(audience) = audience {
+            validation.set_audience(&[audience]);
+        }
+        Self { keys, validation }
+    }
+
+    // The token's algorithm must match the key's family, which prevents verifying an HMAC
+    // signature using a public key as secret.
+    fn verify(&self, token: &str) -> Option<Identity> {
+        let header = decode_header(token).ok()?;
+        let mut validation = self.validation.clone();
+        validation.algorithms = vec![header.alg];
+        self.keys
+            .iter()
+            .filter(|key| header.kid.is_none() || key.id == header.kid)
+            .filter(|key| key.algorithm.is_none() || key.algorithm == Some(header.alg))
+            .find_map(|key| decode::<Claims>(token, &key.key, &validation).ok())
+            .map(|data| Identity::User(data.claims.sub))
+    }
+}
+
+// Rejects unauthenticated requests, and logs every request with the identity of its caller.
+pub async fn middleware<B>(
+    State(config): State<Arc<Config>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let (mut parts, body) = req.into_parts();
+    let (method, path) = (parts.method.clone(), parts.uri.path().to_owned());
+    let (identity, response) = match config.auth.authenticate(&parts) {
+        Ok(identity) => {
+            parts.extensions.insert(identity.clone());
+            (identity, next.run(Request::from_parts(parts, body)).await)
+        }
+        Err(err) => (Identity::Anonymous, err.into_response()),
+    };
+    info!(
+        "{} {} {} {}",
+        method,
+        path,
+        response.status().as_u16(),
+        identity
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Request;
+    use jsonwebtoken::{encode, get_current_timestamp, EncodingKey, Header};
+    use serde_json::json;
+
+    use super::{Authenticator, Identity, JwtKey, JwtVerifier};
+    use crate::Error;
+
+    const SECRET: &[u8] = b"jwt-secret";
+
+    fn authenticator() -> Authenticator {
+        let jwt = JwtVerifier::new(
+            vec![JwtKey {
+                id: Some("main".to_owned()),
+                algorithm: None,
+                key: jsonwebtoken::DecodingKey::from_secret(SECRET),
+            }],
+            Some("https://auth.example.com"),
+            None,
+        );
+        Authenticator::new("alice:key1, bob:key2", Some(jwt)).unwrap()
+    }
+
+    fn authenticate(req: Request<()>) -> Result<Identity, Error> {
+        authenticator().authenticate(&req.into_parts().0)
+    }
+
+    fn jwt(claims: serde_json::Value) -> String {
+        let header = Header {
+            kid: Some("main".to_owned()),
+            ..Default::default()
+        };
+        encode(&header, &claims, &EncodingKey::from_secret(SECRET)).unwrap()
+    }
+
+    #[test]
+    fn disabled() {
+        let identity = Authenticator::default()
+            .authenticate(&Request::get("/door").body(()).unwrap().into_parts().0)
+            .unwrap();
+        assert_eq!(identity, Identity::Anonymous);
+    }
+
+    #[test]
+    fn api_keys() {
+        assert_eq!(
+            authenticate(
+                Request::get("/door")
+                    .header("X-Api-Key", "key2")
+                    .body(())
+                    .unwrap()
+            )
+            .unwrap(),
+            Identity::User("bob".to_owned())
+        );
+        assert_eq!(
+            authenticate(Request::get("/door?apiKey=key1").body(()).unwrap()).unwrap(),
+            Identity::User("alice".to_owned())
+        );
+        assert!(matches!(
+            authenticate(
+                Request::get("/door")
+                    .header("X-Api-Key", "key3")
+                    .body(())
+                    .unwrap()
+            ),
+            Err(Error::Unauthorized)
+        ));
+        assert!(matches!(
+            authenticate(Request::get("/door").body(()).unwrap()),
+            Err(Error::Unauthorized)
+        ));
+        assert!(Authenticator::new("alice", None).is_err());
+    }
+
+    #[test]
+    fn jwt_bearer() {
+        let token = jwt(json!({
+            "sub": "carol",
+            "iss": "https://auth.example.com",
+            "exp": get_current_timestamp() + 60,
+        }));
+        assert_eq!(
+            authenticate(
+                Request::get("/door")
+                    .header("Authorization", format!("Bearer {}", token))
+                    .body(())
+                    .unwrap()
+            )
+            .unwrap(),
+            Identity::User("carol".to_owned())
+        );
+    }
+
+    #[test]
+    fn invalid_jwt() {
+        let expired = jwt(json!({
+            "sub": "carol",
+            "iss": "https://auth.example.com",
+            "exp": get_current_timestamp() - 3600,
+        }));
+        let wrong_issuer = jwt(json!({
+            "sub": "carol",
+            "iss": "https://evil.example.com",
+            "exp": get_current_timestamp() + 60,
+        }));
+        for token in [expired, wrong_issuer, "not-a-jwt".to_owned()] {
+            assert!(matches!(
+                authenticate(
+                    Request::get(format!("/door?accessToken={}", token))
+                        .body(())
+                        .unwrap()
+                ),
+                Err(Error::Unauthorized)
+            ));
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use std::{
+    env, fs,
+    fs::OpenOptions,
+    io::Write,
+    net::IpAddr,
+    path::PathBuf,
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use paho_mqtt::{
+    AsyncClient, ConnectOptions, ConnectOptionsBuilder, CreateOptionsBuilder, Error as MqttError,
+    SslOptionsBuilder, MQTT_VERSION_DEFAULT,
+};
+
+use crate::{
+    config::Config,
+    connect_info::{ConnectInfo, Credentials},
+    misc::is_secure_url,
+    Error,
+};
+
+static PEM_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub fn create(connect_info: &ConnectInfo, config: &Config) -> Result<AsyncClient, Error> {
+    let mut opts = CreateOptionsBuilder::new()
+        .server_uri(connect_info.broker.as_str())
+        .mqtt_version(
+            connect_info
+                .options
+                .mqtt_version(config)
+                .unwrap_or(MQTT_VERSION_DEFAULT),
+        );
+    if let Some(client_id) = &connect_info.options.client_id {
+        opts = opts.client_id(client_id);
+    }
+    AsyncClient::new(opts.finalize()).map_err(|err| Error::ClientInformation.because(err))
+}
+
+pub async fn connect(
+    client: &AsyncClient,
+    connect_info: ConnectInfo,
+    config: &Config,
+) -> Result<(), Error> {
+    let address = config.broker_policy.check(&connect_info.broker).await?;
+    let broker = connect_info.broker.to_string();
+    let (opts, _pem_files) = connect_options(connect_info, address, config)?;
+    client
+        .connect(opts)
+        .await
+        .map_err(|err| connect_error(&err).broker(broker).because(err))?;
+    Ok(())
+}
+
+// The return code of a failed operation: a negative client library error, an MQTT v3 CONNACK
+// return code, or an MQTT v5 reason code.
+fn return_code(err: &MqttError) -> Option<i32> {
+    match err {
+        MqttError::Paho(rc) | MqttError::PahoDescr(rc, _) | MqttError::Publish(rc, _) => Some(*rc),
+        MqttError::ReasonCode(reason) => Some(*reason as i32),
+        _ => None,
+    }
+}
+
+// Distinguishes the failures the caller must fix from the ones worth retrying later.
+fn connect_error(err: &MqttError) -> Error {
+    match (err, return_code(err)) {
+        // v3.1 identifier rejected, v5 client identifier not valid.
+        (_, Some(2 | 133)) => Error::ClientInformation,
+        // Server unavailable, busy, shutting down, connection rate exceeded, moved.
+        (_, Some(3 | 136 | 137 | 139 | 156 | 157 | 159)) => Error::BrokerUnavailable,
+        // Bad user name or password, bad authentication method.
+        (_, Some(4 | 134 | 140)) => Error::BrokerCredentials,
+        // Not authorized, banned.
+        (_, Some(5 | 135 | 138)) => Error::BrokerNotAuthorized,
+        (MqttError::Timeout, _) => Error::BrokerTimeout,
+        // The client library only describes TCP and TLS handshake timeouts.
+        (MqttError::PahoDescr(_, message), _) if message.contains("timeout") => {
+            Error::BrokerTimeout
+        }
+        _ => Error::BrokerConnection,
+    }
+}
+
+// A SUBACK failure is reported as 0x80 by MQTT v3 brokers, and with a reason code by v5 ones.
+pub fn subscribe_error(err: &MqttError) -> Error {
+    match return_code(err) {
+        Some(128 | 135 | 138) => Error::SubscriptionDenied,
+        Some(136 | 137 | 139 | 151) => Error::BrokerUnavailable,
+        _ => Error::Subscription,
+    }
+}
+
+// The C library only reads certificates from the file system, so inlined ones are written to
+// temporary files that must outlive the connection handshake.
+fn connect_options(
+    connect_info: ConnectInfo,
+    address: Option<IpAddr>,
+    config: &Config,
+) -> Result<(ConnectOptions, Vec<PemFile>), Error> {
+    let ConnectInfo {
+        broker,
+        credentials,
+        tls,
+        options,
+    } = connect_info;
+    let mut opts = match options.mqtt_version(config) {
+        Some(version) => ConnectOptionsBuilder::with_mqtt_version(version),
+        None => ConnectOptionsBuilder::new(),
+    };
+    opts.max_inflight(config.max_in_flight as i32);
+    if let Some(keep_alive) = options.keep_alive {
+        opts.keep_alive_interval(Duration::from_secs(keep_alive.into()));
+    }
+    if let Some(Credentials { username, password }) = credentials {
+        opts.user_name(username).password(password);
+    }
+    // Connects to the address checked by the broker policy instead of resolving the host name
+    // again. Verified TLS connections keep the host name, which certificates are checked against.
+    if let Some(address) = address.filter(|_| !is_secure_url(&broker) || tls.insecure) {
+        let mut url = broker.clone();
+        if url.set_ip_host(address).is_ok() {
+            opts.server_uris(&[url.as_str()]);
+        }
+    }
+
+    let mut pem_files = Vec::new();
+    if is_secure_url(&broker) {
+        let mut ssl = SslOptionsBuilder::new();
+        if let Some(ca_file) = &config.ca_file {
+            ssl.trust_store(ca_file)
+                .map_err(|err| Error::Tls.because(err))?;
+        }
+        if let Some(cert) = tls.client_cert {
+            let cert_file = PemFile::write(&cert)?;
+            ssl.key_store(&cert_file.0)
+                .map_err(|err| Error::Tls.because(err))?;
+            pem_files.push(cert_file);
+        }
+        if let Some(key) = tls.client_key {
+            let key_file = PemFile::write(&key)?;
+            ssl.private_key(&key_file.0)
+                .map_err(|err| Error::Tls.because(err))?;
+            pem_files.push(key_file);
+        }
+        if tls.insecure {
+            ssl.enable_server_cert_auth(false).verify(false);
+        }
+        opts.ssl_options(ssl.finalize());
+    }
+
+    Ok((opts.finalize(), pem_files))
+}
+
+struct PemFile(PathBuf);
+
+impl PemFile {
+    fn write(content: &str) -> Result<Self, Error> {
+        let path = env::temp_dir().join(format!(
+            "httq-{}-{}.pem",
+            process::id(),
+            PEM_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(&path)
+            .and_then(|mut file| file.write_all(content.as_bytes()))
+            .map_err(|err| Error::Tls.because(err))?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for PemFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use paho_mqtt::{Error as MqttError, ReasonCode};
+
+    use super::{connect_error, subscribe_error};
+    use crate::Error;
+
+    #[test]
+    fn connect_errors() {
+        assert!(matches!(
+            connect_error(&MqttError::Paho(4)),
+            Error::BrokerCredentials
+        ));
+        assert!(matches!(
+            connect_error(&MqttError::PahoDescr(135, "Not authorized".to_owned())),
+            Error::BrokerNotAuthorized
+        ));
+        assert!(matches!(
+            connect_error(&MqttError::Paho(3)),
+            Error::BrokerUnavailable
+        ));
+        assert!(matches!(
+            connect_error(&MqttError::PahoDescr(-1, "TCP connect timeout".to_owned())),
+            Error::BrokerTimeout
+        ));
+        assert!(matches!(
+            connect_error(&MqttError::PahoDescr(
+                -1,
+                "TCP/TLS connect failure".to_owned()
+            )),
+            Error::BrokerConnection
+        ));
+    }
+
+    #[test]
+    fn subscribe_errors() {
+        assert!(matches!(
+            subscribe_error(&MqttError::Paho(128)),
+            Error::SubscriptionDenied
+        ));
+        assert!(matches!(
+            subscribe_error(&MqttError::ReasonCode(ReasonCode::NotAuthorized)),
+            Error::SubscriptionDenied
+        ));
+        assert!(matches!(
+            subscribe_error(&MqttError::Paho(-3)),
+            Error::Subscription
+        ));
+    }
+}
@@ -0,0 +1,502 @@
+use std::{
+    collections::HashMap,
+    env,
+    error::Error as StdError,
+    fs,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use log::LevelFilter;
+use paho_mqtt::{MQTT_VERSION_3_1, MQTT_VERSION_5};
+
+use crate::{
+    acl::Acl,
+    auth::{Authenticator, JwtVerifier},
+    layout::Layouts,
+    misc::parse_bool,
+    policy::BrokerPolicy,
+    profile::Profiles,
+};
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+// Every setting, named `name` in the configuration file, `--name` (with dashes) on the command
+// line and `HTTQ_NAME` in the environment.
+#[rustfmt::skip]
+const SETTINGS: [(&str, &str); 30] = [
+    ("config_file", "TOML file providing any of these settings"),
+    ("bind", "Comma separated IPv4 or IPv6 addresses to listen on"),
+    ("port", "Port to listen on"),
+    ("max_body_size", "Maximum request body size, in bytes"),
+    ("worker_threads", "Runtime worker threads"),
+    ("log_level", "Log level: off, error, warn, info, debug or trace"),
+    ("enable_publish", "Enables the publish and retain routes"),
+    ("enable_subscribe", "Enables the subscribe route"),
+    ("enable_websocket", "Enables WebSocket upgrades of the subscribe route"),
+    ("ca_file", "CA bundle used to verify secure brokers"),
+    ("pool_idle_timeout", "Seconds before an unused publish connection is closed"),
+    ("subscribe_timeout", "Default subscribe wait, in seconds"),
+    ("max_subscribe_timeout", "Maximum subscribe wait, in seconds"),
+    ("max_subscribe_count", "Maximum number of messages returned by a subscribe request"),
+    ("mqtt_version", "Default MQTT version (3, 4 or 5) used for every broker"),
+    ("publish_timeout", "Default time allowed to publish to a broker, in seconds"),
+    ("max_publish_timeout", "Maximum time allowed to publish to a broker, in seconds"),
+    ("publish_concurrency", "Maximum number of brokers published to concurrently"),
+    ("max_in_flight", "Maximum number of unacknowledged messages per broker"),
+    ("strict_payloads", "Rejects unknown fields, unknown payload types and invalid payloads"),
+    ("query_publish_token", "Enables the query string publish route, guarded by this token"),
+    ("profiles_file", "TOML file defining broker profiles"),
+    ("layouts_file", "TOML file defining struct payload layouts"),
+    ("allowed_brokers", "Brokers that can be connected to"),
+    ("denied_brokers", "Brokers that can't be connected to"),
+    ("api_keys", "Comma separated name:key API keys"),
+    ("jwt_key_file", "JWKS file or PEM public key verifying JWT bearer tokens"),
+    ("jwt_issuer", "Required JWT issuer"),
+    ("jwt_audience", "Required JWT audience"),
+    ("acl_file", "TOML file restricting the topics and brokers of each identity"),
+];
+
+pub struct Config {
+    pub bind: Vec<IpAddr>,
+    pub port: u16,
+    pub max_body_size: usize,
+    // A single threaded runtime is used when 1.
+    pub worker_threads: usize,
+    pub log_level: LevelFilter,
+    pub enable_publish: bool,
+    pub enable_subscribe: bool,
+    pub enable_websocket: bool,
+    pub ca_file: Option<PathBuf>,
+    pub pool_idle_timeout: Duration,
+    pub subscribe_timeout: Duration,
+    pub max_subscribe_timeout: Duration,
+    pub max_subscribe_count: usize,
+    pub mqtt_version: Option<u32>,
+    pub publish_timeout: Duration,
+    pub max_publish_timeout: Duration,
+    pub publish_concurrency: usize,
+    pub max_in_flight: usize,
+    // Default of the requests not enabling or disabling strict validation themselves.
+    pub strict_payloads: bool,
+    // Enables the GET publish route when set.
+    pub query_publish_token: Option<String>,
+    pub profiles: Profiles,
+    pub layouts: Layouts,
+    pub broker_policy: BrokerPolicy,
+    pub auth: Authenticator,
+    pub acl: Acl,
+}
+
+impl Config {
+    pub fn load(settings: &Settings) -> Result<Self, BoxError> {
+        let mut config = Self::default();
+        if let Some(bind) = settings.get("bind") {
+            config.bind = bind
+                .split(',')
+                .map(|ip| ip.trim().parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| "invalid bind value")?;
+            if config.bind.is_empty() {
+                return Err("invalid bind value".into());
+            }
+        }
+        if let Some(port) = settings.parse("port")? {
+            config.port = port;
+        }
+        if let Some(size) = settings.parse("max_body_size")? {
+            config.max_body_size = size;
+        }
+        if let Some(threads) = settings.parse("worker_threads")? {
+            if threads == 0 {
+                return Err("invalid worker_threads value".into());
+            }
+            config.worker_threads = threads;
+        }
+        if let Some(level) = settings.parse("log_level")? {
+            config.log_level = level;
+        }
+        if let Some(enabled) = settings.bool("enable_publish")? {
+            config.enable_publish = enabled;
+        }
+        if let Some(enabled) = settings.bool("enable_subscribe")? {
+            config.enable_subscribe = enabled;
+        }
+        if let Some(enabled) = settings.bool("enable_websocket")? {
+            config.enable_websocket = enabled;
+        }
+        if !config.enable_publish && !config.enable_subscribe {
+            return Err("publish and subscribe can't both be disabled".into());
+        }
+        if let Some(path) = settings.path("ca_file") {
+            if !path.is_file() {
+                return Err(format!("CA file {} not found", path.display()).into());
+            }
+            config.ca_file = Some(path);
+        }
+        if let Some(timeout) = settings.secs("pool_idle_timeout")? {
+            config.pool_idle_timeout = timeout;
+        }
+        if let Some(timeout) = settings.secs("max_subscribe_timeout")? {
+            config.max_subscribe_timeout = timeout;
+        }
+        config.subscribe_timeout = settings
+            .secs("subscribe_timeout")?
+            .unwrap_or(config.max_subscribe_timeout);
+        if config.subscribe_timeout > config.max_subscribe_timeout {
+            return Err("subscribe_timeout exceeds max_subscribe_timeout".into());
+        }
+        if let Some(count) = settings.parse("max_subscribe_count")? {
+            config.max_subscribe_count = count;
+        }
+        if let Some(version) = settings.parse("mqtt_version")? {
+            if !(MQTT_VERSION_3_1..=MQTT_VERSION_5).contains(&version) {
+                return Err("invalid mqtt_version value".into());
+            }
+            config.mqtt_version = Some(version);
+        }
+        if let Some(timeout) = settings.secs("max_publish_timeout")? {
+            config.max_publish_timeout = timeout;
+        }
+        config.publish_timeout = settings
+            .secs("publish_timeout")?
+            .unwrap_or(config.max_publish_timeout);
+        if config.publish_timeout > config.max_publish_timeout {
+            return Err("publish_timeout exceeds max_publish_timeout".into());
+        }
+        if let Some(concurrency) = settings.parse("publish_concurrency")? {
+            if concurrency == 0 {
+                return Err("invalid publish_concurrency value".into());
+            }
+            config.publish_concurrency = concurrency;
+        }
+        if let Some(max_in_flight) = settings.parse("max_in_flight")? {
+            if !(1..=u16::MAX as usize).contains(&max_in_flight) {
+                return Err("invalid max_in_flight value".into());
+            }
+            config.max_in_flight = max_in_flight;
+        }
+        if let Some(strict) = settings.bool("strict_payloads")? {
+            config.strict_payloads = strict;
+        }
+        config.query_publish_token = settings
+            .get("query_publish_token")
+            .filter(|token| !token.is_empty());
+        if let Some(path) = settings.path("profiles_file") {
+            config.profiles = Profiles::load(&path)
+                .map_err(|err| format!("invalid profiles file {}: {}", path.display(), err))?;
+        }
+        if let Some(path) = settings.path("layouts_file") {
+            config.layouts = Layouts::load(&path)
+                .map_err(|err| format!("invalid layouts file {}: {}", path.display(), err))?;
+        }
+        config.broker_policy = BrokerPolicy::new(
+            &settings.get("allowed_brokers").unwrap_or_default(),
+            &settings.get("denied_brokers").unwrap_or_default(),
+        )?;
+        let jwt = settings
+            .path("jwt_key_file")
+            .map(|path| {
+                JwtVerifier::load(
+                    &path,
+                    settings.get("jwt_issuer").as_deref(),
+                    settings.get("jwt_audience").as_deref(),
+                )
+                .map_err(|err| format!("invalid JWT key file {}: {}", path.display(), err))
+            })
+            .transpose()?;
+        config.auth = Authenticator::new(&settings.get("api_keys").unwrap_or_default(), jwt)?;
+        if let Some(path) = settings.path("acl_file") {
+            if !config.auth.is_enabled() {
+                return Err("acl_file requires authentication to be enabled".into());
+            }
+            config.acl = Acl::load(&path)
+                .map_err(|err| format!("invalid ACL file {}: {}", path.display(), err))?;
+        }
+        Ok(config)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
+            port: 8080,
+            max_body_size: 16_777_216,
+            worker_threads: 1,
+            log_level: LevelFilter::Info,
+            enable_publish: true,
+            enable_subscribe: true,
+            enable_websocket: true,
+            ca_file: None,
+            pool_idle_timeout: Duration::from_secs(60),
+            subscribe_timeout: Duration::from_secs(5 * 60),
+            max_subscribe_timeout: Duration::from_secs(5 * 60),
+            max_subscribe_count: 100,
+            mqtt_version: None,
+            publish_timeout: Duration::from_secs(30),
+            max_publish_timeout: Duration::from_secs(30),
+            publish_concurrency: 8,
+            max_in_flight: 64,
+            strict_payloads: true,
+            query_publish_token: None,
+            profiles: Profiles::default(),
+            layouts: Layouts::default(),
+            broker_policy: BrokerPolicy::default(),
+            auth: Authenticator::default(),
+            acl: Acl::default(),
+        }
+    }
+}
+
+// Raw setting values, looked up in command line flags, then environment variables, then the
+// configuration file.
+#[derive(Default)]
+pub struct Settings {
+    flags: HashMap<String, String>,
+    env: HashMap<String, String>,
+    file: HashMap<String, String>,
+}
+
+impl Settings {
+    pub fn load(args: impl IntoIterator<Item = String>) -> Result<Self, BoxError> {
+        let mut settings = Self {
+            flags: parse_flags(args)?,
+            env: env::vars()
+                .filter(|(name, _)| name.starts_with("HTTQ_"))
+                .collect(),
+            file: HashMap::new(),
+        };
+        if let Some(path) = settings.path("config_file") {
+            settings.file = read_file(&path)
+                .map_err(|err| format!("invalid config file {}: {}", path.display(), err))?;
+        }
+        Ok(settings)
+    }
+
+    pub fn usage() -> String {
+        let mut usage = "Usage: httq [--name value]...\n\nSettings:\n".to_owned();
+        for (name, description) in SETTINGS {
+            let flag = format!("--{}", name.replace('_', "-"));
+            usage += &format!("  {:<26}{}\n", flag, description);
+        }
+        usage +=
+            "\nEach setting can also be set using the HTTQ_<NAME> environment variable, or in \
+                  the configuration file.\n";
+        usage
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        self.flags
+            .get(name)
+            .or_else(|| self.env.get(&format!("HTTQ_{}", name.to_ascii_uppercase())))
+            .or_else(|| self.file.get(name))
+            .cloned()
+    }
+
+    fn parse<T: FromStr>(&self, name: &str) -> Result<Option<T>, BoxError> {
+        self.get(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| format!("invalid {} value", name).into())
+            })
+            .transpose()
+    }
+
+    fn bool(&self, name: &str) -> Result<Option<bool>, BoxError> {
+        self.get(name)
+            .map(|value| parse_bool(&value).ok_or_else(|| format!("invalid {} value", name).into()))
+            .transpose()
+    }
+
+    fn secs(&self, name: &str) -> Result<Option<Duration>, BoxError> {
+        Ok(self.parse(name)?.map(Duration::from_secs))
+    }
+
+    fn path(&self, name: &str) -> Option<PathBuf> {
+        self.get(name).map(PathBuf::from)
+    }
+}
+
+fn is_setting(name: &str) -> bool {
+    SETTINGS.iter().any(|(setting, _)| *setting == name)
+}
+
+// Flags are given as `--name value` or `--name=value`.
+fn parse_flags(
+    args: impl IntoIterator<Item = String>,
+) -> Result<HashMap<String, String>, BoxError> {
+    let mut flags = HashMap::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let flag = arg
+            .strip_prefix("--")
+            .ok_or_else(|| format!("unexpected argument {}", arg))?;
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name, value.to_owned()),
+            None => (
+                flag,
+                args.next()
+                    .ok_or_else(|| format!("missing value for {}", arg))?,
+            ),
+        };
+        let name = name.replace('-', "_");
+        if !is_setting(&name) {
+            return Err(format!("unknown flag --{}", name.replace('_', "-")).into());
+        }
+        flags.insert(name, value);
+    }
+    Ok(flags)
+}
+
+// Arrays are joined by commas, like the values of list settings given as flags.
+fn read_file(path: &Path) -> Result<HashMap<String, String>, BoxError> {
+    let table: toml::Table = toml::from_str(&fs::read_to_string(path)?)?;
+    table
+        .into_iter()
+        .map(|(name, value)| {
+            if !is_setting(&name) || name == "config_file" {
+                return Err(format!("unknown setting {}", name).into());
+            }
+            let value = match value {
+                toml::Value::Array(values) => values
+                    .into_iter()
+                    .map(|value| toml_str(&name, value))
+                    .collect::<Result<Vec<_>, _>>()?
+                    .join(","),
+                value => toml_str(&name, value)?,
+            };
+            Ok((name, value))
+        })
+        .collect()
+}
+
+fn toml_str(name: &str, value: toml::Value) -> Result<String, BoxError> {
+    match value {
+        toml::Value::String(value) => Ok(value),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        _ => Err(format!("invalid {} value", name).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::{IpAddr, Ipv6Addr},
+        time::Duration,
+    };
+
+    use log::LevelFilter;
+
+    use super::{parse_flags, Config, Settings};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn settings(flags: &[&str], env: &[(&str, &str)], file: &[(&str, &str)]) -> Settings {
+        let map = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+        Settings {
+            flags: parse_flags(args(flags)).unwrap(),
+            env: map(env),
+            file: map(file),
+        }
+    }
+
+    #[test]
+    fn flags() {
+        let flags = parse_flags(args(&["--port", "9090", "--max-body-size=1024"])).unwrap();
+        assert_eq!(flags["port"], "9090");
+        assert_eq!(flags["max_body_size"], "1024");
+        assert!(parse_flags(args(&["--prot", "9090"])).is_err());
+        assert!(parse_flags(args(&["--port"])).is_err());
+        assert!(parse_flags(args(&["9090"])).is_err());
+    }
+
+    #[test]
+    fn precedence() {
+        let settings = settings(
+            &["--port", "1"],
+            &[("HTTQ_PORT", "2"), ("HTTQ_WORKER_THREADS", "4")],
+            &[
+                ("port", "3"),
+                ("worker_threads", "3"),
+                ("bind", "::1,127.0.0.1"),
+            ],
+        );
+        let config = Config::load(&settings).unwrap();
+        assert_eq!(config.port, 1);
+        assert_eq!(config.worker_threads, 4);
+        assert_eq!(
+            config.bind,
+            [
+                IpAddr::V6(Ipv6Addr::LOCALHOST),
+                "127.0.0.1".parse::<IpAddr>().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn timeouts() {
+        let config = Config::load(&settings(&["--publish-timeout", "5"], &[], &[])).unwrap();
+        assert_eq!(config.publish_timeout, Duration::from_secs(5));
+        assert_eq!(config.subscribe_timeout, config.max_subscribe_timeout);
+        assert!(Config::load(&settings(&["--subscribe-timeout", "3600"], &[], &[])).is_err());
+    }
+
+    #[test]
+    fn validation() {
+        for flags in [
+            ["--bind", "localhost"],
+            ["--port", "65536"],
+            ["--worker-threads", "0"],
+            ["--enable-websocket", "maybe"],
+            ["--mqtt-version", "6"],
+        ] {
+            assert!(Config::load(&settings(&flags, &[], &[])).is_err());
+        }
+        let disabled = settings(
+            &["--enable-publish", "false", "--enable-subscribe", "false"],
+            &[],
+            &[],
+        );
+        assert!(Config::load(&disabled).is_err());
+    }
+
+    #[test]
+    fn toggles() {
+        let config = Config::load(&settings(&[], &[], &[])).unwrap();
+        assert!(config.enable_publish && config.enable_subscribe && config.enable_websocket);
+        let config = Config::load(&settings(
+            &["--enable-publish", "false"],
+            &[("HTTQ_ENABLE_WEBSOCKET", "false")],
+            &[],
+        ))
+        .unwrap();
+        assert!(!config.enable_publish);
+        assert!(config.enable_subscribe);
+        assert!(!config.enable_websocket);
+    }
+
+    #[test]
+    fn log_level() {
+        let config = Config::load(&settings(&[], &[], &[])).unwrap();
+        assert_eq!(config.log_level, LevelFilter::Info);
+        let config = Config::load(&settings(&["--log-level", "warn"], &[], &[])).unwrap();
+        assert_eq!(config.log_level, LevelFilter::Warn);
+        let config = Config::load(&settings(&[], &[("HTTQ_LOG_LEVEL", "OFF")], &[])).unwrap();
+        assert_eq!(config.log_level, LevelFilter::Off);
+        assert!(Config::load(&settings(&["--log-level", "verbose"], &[], &[])).is_err());
+    }
+}
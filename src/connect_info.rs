@@ -1,61 +1,445 @@
+use std::sync::Arc;
+
 use axum::{
     async_trait,
-    extract::FromRequestParts,
-    http::{request::Parts, Uri},
+    extract::{FromRef, FromRequestParts},
+    http::{request::Parts, HeaderMap},
 };
-use serde::Deserialize;
+use base64::engine::{general_purpose::STANDARD as BASE64, Engine as _};
+use paho_mqtt::{MQTT_VERSION_3_1, MQTT_VERSION_5};
+use percent_encoding::percent_decode_str;
+use serde::{de::Unexpected, Deserialize, Deserializer};
 use url::Url;
 
 use crate::{
-    misc::{header_str, parse_url_with_default},
+    config::Config,
+    misc::{header_str, parse_bool, query_param},
+    profile::Target,
     Error,
 };
 
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
 pub struct ConnectInfo {
     pub broker: Url,
     pub credentials: Option<Credentials>,
+    pub tls: TlsOptions,
+    pub options: ClientOptions,
 }
 
-#[async_trait]
-impl FromRequestParts<()> for ConnectInfo {
-    type Rejection = Error;
+impl ConnectInfo {
+    pub fn resolve(
+        target: Target,
+        credentials: Option<Credentials>,
+        tls: TlsOptions,
+        options: ClientOptions,
+        config: &Config,
+    ) -> Result<Self, Error> {
+        Ok(match target {
+            Target::Url(broker) => Self {
+                broker,
+                credentials,
+                tls,
+                options,
+            },
+            // Requests could otherwise disable the verification of the profile's broker, or
+            // connect using another client certificate.
+            Target::Profile(_) if tls != TlsOptions::default() => {
+                return Err(Error::Tls.because("TLS options can't be set with a broker profile"))
+            }
+            Target::Profile(name) => config
+                .profiles
+                .get(&name)
+                .ok_or(Error::Profile)?
+                .connect_info(credentials, options),
+        })
+    }
 
-    async fn from_request_parts(parts: &mut Parts, _state: &()) -> Result<Self, Self::Rejection> {
-        Ok(Self {
-            broker: parse_url_with_default(
-                header_str(&parts.headers, "X-Broker").ok_or(Error::Header)?,
-            )
-            .map_err(|_| Error::BrokerUrl)?,
-            credentials: header_str(&parts.headers, "X-Username").and_then(|username| {
+    // Headers take precedence over query parameters, used by clients unable to set headers.
+    // Credentials are only read from the query string when allowed, as URLs end up in logs:
+    // clients unable to set headers use profiles otherwise.
+    fn from_parts(parts: &Parts, config: &Config, query_credentials: bool) -> Result<Self, Error> {
+        let param = |header, name| {
+            header_str(&parts.headers, header)
+                .map(str::to_owned)
+                .or_else(|| query_param(&parts.uri, name))
+        };
+        let credential = |header, name| match query_credentials {
+            true => param(header, name),
+            false => header_str(&parts.headers, header).map(str::to_owned),
+        };
+        let target = match param("X-Broker-Profile", "brokerProfile") {
+            Some(name) => Target::Profile(name),
+            None => match param("X-Broker", "broker") {
+                Some(broker) => broker
+                    .parse()
+                    .map_err(|err| Error::BrokerUrl.field("X-Broker").because(err))?,
+                None => Target::Profile(
+                    config
+                        .profiles
+                        .default
+                        .clone()
+                        .ok_or_else(|| Error::Header.field("X-Broker"))?,
+                ),
+            },
+        };
+        Self::resolve(
+            target,
+            credential("X-Username", "username").and_then(|username| {
                 Some(Credentials {
-                    username: username.to_owned(),
-                    password: header_str(&parts.headers, "X-Password")?.to_owned(),
+                    username,
+                    password: credential("X-Password", "password")?,
                 })
             }),
-        })
+            TlsOptions::from_headers(&parts.headers)?,
+            ClientOptions::from_parts(parts)?,
+            config,
+        )
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ConnectInfo
+where
+    Arc<Config>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Self::from_parts(parts, &Arc::<Config>::from_ref(state), false)
+    }
+}
+
+// Connection info also reading credentials from the query string, for the token guarded query
+// publish route.
+pub struct QueryConnectInfo(pub ConnectInfo);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for QueryConnectInfo
+where
+    Arc<Config>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        ConnectInfo::from_parts(parts, &Arc::<Config>::from_ref(state), true).map(Self)
     }
 }
 
-#[derive(Deserialize, PartialEq, Debug)]
+#[derive(Deserialize, PartialEq, Eq, Hash, Clone, Debug)]
 pub struct Credentials {
     pub username: String,
     pub password: String,
 }
 
+#[derive(Deserialize, PartialEq, Eq, Hash, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TlsOptions {
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    #[serde(default)]
+    pub insecure: bool,
+}
+
+impl TlsOptions {
+    // PEM files can't be sent as is in headers (new lines), so they are expected to be base64 encoded.
+    fn from_headers(headers: &HeaderMap) -> Result<Self, Error> {
+        let pem_header = |name| {
+            header_str(headers, name)
+                .map(|encoded| {
+                    BASE64
+                        .decode(encoded)
+                        .ok()
+                        .and_then(|decoded| String::from_utf8(decoded).ok())
+                        .ok_or(Error::Tls)
+                })
+                .transpose()
+        };
+        Ok(Self {
+            client_cert: pem_header("X-Client-Cert")?,
+            client_key: pem_header("X-Client-Key")?,
+            insecure: header_str(headers, "X-Insecure")
+                .map(|insecure| {
+                    parse_bool(insecure).ok_or_else(|| Error::Header.field("X-Insecure"))
+                })
+                .transpose()?
+                .unwrap_or(false),
+        })
+    }
+}
+
+#[derive(Deserialize, PartialEq, Eq, Hash, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientOptions {
+    #[serde(default, deserialize_with = "ClientOptions::deserialize_mqtt_version")]
+    pub mqtt_version: Option<u32>,
+    pub client_id: Option<String>,
+    // In seconds, sent as a two bytes integer.
+    pub keep_alive: Option<u16>,
+}
+
+impl ClientOptions {
+    fn from_parts(parts: &Parts) -> Result<Self, Error> {
+        Ok(Self {
+            mqtt_version: header_str(&parts.headers, "X-Mqtt-Version")
+                .map(str::to_owned)
+                .or_else(|| query_param(&parts.uri, "mqttVersion"))
+                .map(|version| {
+                    version
+                        .parse()
+                        .ok()
+                        .filter(Self::is_valid_mqtt_version)
+                        .ok_or_else(|| Error::Header.field("X-Mqtt-Version"))
+                })
+                .transpose()?,
+            client_id: header_str(&parts.headers, "X-Client-Id").map(str::to_owned),
+            keep_alive: header_str(&parts.headers, "X-Keep-Alive")
+                .map(|secs| {
+                    secs.parse()
+                        .map_err(|err| Error::Header.field("X-Keep-Alive").because(err))
+                })
+                .transpose()?,
+        })
+    }
+
+    fn deserialize_mqtt_version<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let version = u32::deserialize(deserializer)?;
+        if Self::is_valid_mqtt_version(&version) {
+            Ok(Some(version))
+        } else {
+            Err(serde::de::Error::invalid_value(
+                Unexpected::Unsigned(version as u64),
+                &"MQTT version 3 (3.1), 4 (3.1.1) or 5",
+            ))
+        }
+    }
+
+    fn is_valid_mqtt_version(version: &u32) -> bool {
+        (MQTT_VERSION_3_1..=MQTT_VERSION_5).contains(version)
+    }
+
+    // The protocol version requested by the client, or the server's default one.
+    pub fn mqtt_version(&self, config: &Config) -> Option<u32> {
+        self.mqtt_version.or(config.mqtt_version)
+    }
+}
+
 pub struct Topic(pub String);
 
+impl Topic {
+    // '#' starts the fragment part of an URI and is never sent by clients, so wildcards can either
+    // be percent-encoded in the path or specified using the query string or a header.
+    fn from_parts(parts: &Parts) -> Result<Self, Error> {
+        if let Some(topic) = header_str(&parts.headers, "X-Topic") {
+            return Ok(Self(topic.to_owned()));
+        }
+        if let Some(topic) = query_param(&parts.uri, "topic") {
+            return Ok(Self(topic));
+        }
+        Ok(Self(
+            percent_decode_str(parts.uri.path().trim_start_matches('/'))
+                .decode_utf8()
+                .map_err(|err| Error::Topic.because(err))?
+                .into_owned(),
+        ))
+    }
+}
+
 #[async_trait]
-impl FromRequestParts<()> for Topic {
+impl<S: Send + Sync> FromRequestParts<S> for Topic {
     type Rejection = Error;
 
-    async fn from_request_parts(parts: &mut Parts, _state: &()) -> Result<Self, Self::Rejection> {
-        Ok(Self(
-            Uri::from_request_parts(parts, &())
-                .await
-                .map_err(|_| Error::Topic)?
-                .path()
-                .trim_start_matches('/')
-                .to_owned(),
-        ))
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Self::from_parts(parts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Request;
+
+    use super::{ConnectInfo, Credentials, Topic};
+    use crate::{config::Config, Error};
+
+    fn topic(req: Request<()>) -> String {
+        Topic::from_parts(&req.into_parts().0).unwrap().0
+    }
+
+    #[test]
+    fn path() {
+        assert_eq!(
+            topic(Request::get("/sensors/kitchen").body(()).unwrap()),
+            "sensors/kitchen"
+        );
+    }
+
+    #[test]
+    fn percent_encoded_path() {
+        assert_eq!(
+            topic(Request::get("/sensors/%2B/temp%20c").body(()).unwrap()),
+            "sensors/+/temp c"
+        );
+        assert_eq!(
+            topic(Request::get("/sensors/%23").body(()).unwrap()),
+            "sensors/#"
+        );
+    }
+
+    #[test]
+    fn query() {
+        assert_eq!(
+            topic(
+                Request::get("/ignored?qos=1&topic=sensors%2F%23")
+                    .body(())
+                    .unwrap()
+            ),
+            "sensors/#"
+        );
+    }
+
+    #[test]
+    fn header() {
+        assert_eq!(
+            topic(
+                Request::get("/ignored?topic=ignored")
+                    .header("X-Topic", "sensors/#")
+                    .body(())
+                    .unwrap()
+            ),
+            "sensors/#"
+        );
+    }
+
+    fn profiles_config() -> Config {
+        Config {
+            profiles: toml::from_str(
+                r#"
+                default = "prod"
+
+                [profiles.prod]
+                url = "mqtts://prod.broker.com"
+
+                [profiles.staging]
+                url = "staging.broker.com"
+                "#,
+            )
+            .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn broker(req: Request<()>, config: &Config) -> Result<String, Error> {
+        ConnectInfo::from_parts(&req.into_parts().0, config, false)
+            .map(|info| info.broker.to_string())
+    }
+
+    #[test]
+    fn broker_header() {
+        assert_eq!(
+            broker(
+                Request::get("/door")
+                    .header("X-Broker", "broker.com")
+                    .body(())
+                    .unwrap(),
+                &profiles_config()
+            )
+            .unwrap(),
+            "tcp://broker.com"
+        );
+        let missing = broker(Request::get("/door").body(()).unwrap(), &Config::default());
+        assert!(matches!(
+            missing.as_ref().map_err(Error::kind),
+            Err(Error::Header)
+        ));
+    }
+
+    #[test]
+    fn broker_profile() {
+        let config = profiles_config();
+        assert_eq!(
+            broker(Request::get("/door").body(()).unwrap(), &config).unwrap(),
+            "mqtts://prod.broker.com"
+        );
+        assert_eq!(
+            broker(
+                Request::get("/door")
+                    .header("X-Broker-Profile", "staging")
+                    .body(())
+                    .unwrap(),
+                &config
+            )
+            .unwrap(),
+            "tcp://staging.broker.com"
+        );
+        assert_eq!(
+            broker(
+                Request::get("/door")
+                    .header("X-Broker", "@staging")
+                    .body(())
+                    .unwrap(),
+                &config
+            )
+            .unwrap(),
+            "tcp://staging.broker.com"
+        );
+        assert!(matches!(
+            broker(
+                Request::get("/door")
+                    .header("X-Broker-Profile", "dev")
+                    .body(())
+                    .unwrap(),
+                &config
+            ),
+            Err(Error::Profile)
+        ));
+    }
+
+    #[test]
+    fn profile_tls() {
+        let insecure = broker(
+            Request::get("/door")
+                .header("X-Broker-Profile", "prod")
+                .header("X-Insecure", "true")
+                .body(())
+                .unwrap(),
+            &profiles_config(),
+        );
+        assert!(matches!(
+            insecure.as_ref().map_err(Error::kind),
+            Err(Error::Tls)
+        ));
+        assert!(broker(
+            Request::get("/door")
+                .header("X-Broker", "broker.com")
+                .header("X-Insecure", "true")
+                .body(())
+                .unwrap(),
+            &profiles_config()
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn query_credentials() {
+        let credentials = |query_credentials| {
+            let req = Request::get("/door?broker=broker.com&username=user&password=pass")
+                .body(())
+                .unwrap();
+            ConnectInfo::from_parts(&req.into_parts().0, &Config::default(), query_credentials)
+                .unwrap()
+                .credentials
+        };
+        assert_eq!(credentials(false), None);
+        assert_eq!(
+            credentials(true),
+            Some(Credentials {
+                username: "user".to_owned(),
+                password: "pass".to_owned(),
+            })
+        );
     }
 }
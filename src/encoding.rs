@@ -0,0 +1,245 @@
+use std::{fmt, str::FromStr};
+
+use serde_json::{Number, Value};
+
+// A fixed-width number, named like `u8`, `i32le` or `f64be`. Multi-byte types must specify their
+// byte order, as devices disagree on it.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct NumberType {
+    kind: NumberKind,
+    // In bytes.
+    width: usize,
+    little_endian: bool,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum NumberKind {
+    Unsigned,
+    Signed,
+    Float,
+}
+
+impl NumberType {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn encode(&self, number: &Number) -> Result<Vec<u8>, String> {
+        let bits = self.width * 8;
+        let out_of_range = || format!("{} out of range for {}", number, self);
+        let value = match self.kind {
+            NumberKind::Unsigned => number
+                .as_u64()
+                .filter(|value| bits == 64 || *value >> bits == 0)
+                .ok_or_else(out_of_range)?,
+            NumberKind::Signed => {
+                let value = number
+                    .as_i64()
+                    .filter(|value| bits == 64 || matches!(*value >> (bits - 1), 0 | -1))
+                    .ok_or_else(out_of_range)?;
+                value as u64
+            }
+            NumberKind::Float => {
+                let value = number.as_f64().ok_or_else(out_of_range)?;
+                match self.width {
+                    4 if (value as f32).is_finite() => (value as f32).to_bits() as u64,
+                    4 => return Err(out_of_range()),
+                    _ => value.to_bits(),
+                }
+            }
+        };
+        // The value's low bytes, in the requested order.
+        Ok(if self.little_endian {
+            value.to_le_bytes()[..self.width].to_vec()
+        } else {
+            value.to_be_bytes()[8 - self.width..].to_vec()
+        })
+    }
+
+    // Reads a number from exactly `width` bytes, floats that aren't finite being unrepresentable
+    // in JSON.
+    pub fn decode(&self, bytes: &[u8]) -> Option<Number> {
+        if bytes.len() != self.width {
+            return None;
+        }
+        let mut buffer = [0; 8];
+        let value = if self.little_endian {
+            buffer[..self.width].copy_from_slice(bytes);
+            u64::from_le_bytes(buffer)
+        } else {
+            buffer[8 - self.width..].copy_from_slice(bytes);
+            u64::from_be_bytes(buffer)
+        };
+        let shift = 64 - self.width * 8;
+        match self.kind {
+            NumberKind::Unsigned => Some(value.into()),
+            // Sign extends the value.
+            NumberKind::Signed => Some((((value << shift) as i64) >> shift).into()),
+            NumberKind::Float if self.width == 4 => {
+                Number::from_f64(f32::from_bits(value as u32) as f64)
+            }
+            NumberKind::Float => Number::from_f64(f64::from_bits(value)),
+        }
+    }
+}
+
+impl FromStr for NumberType {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (kind, rest) = match input.split_at(input.len().min(1)) {
+            ("u", rest) => (NumberKind::Unsigned, rest),
+            ("i", rest) => (NumberKind::Signed, rest),
+            ("f", rest) => (NumberKind::Float, rest),
+            _ => return Err(()),
+        };
+        let (bits, little_endian) = match rest.strip_suffix("le") {
+            Some(bits) => (bits, Some(true)),
+            None => match rest.strip_suffix("be") {
+                Some(bits) => (bits, Some(false)),
+                None => (rest, None),
+            },
+        };
+        let width = match (kind, bits) {
+            (NumberKind::Float, "32") => 4,
+            (NumberKind::Float, "64") => 8,
+            (NumberKind::Float, _) => return Err(()),
+            (_, "8") => 1,
+            (_, "16") => 2,
+            (_, "32") => 4,
+            (_, "64") => 8,
+            _ => return Err(()),
+        };
+        let little_endian = match (width, little_endian) {
+            (1, None) => true,
+            (1, Some(_)) | (_, None) => return Err(()),
+            (_, Some(little_endian)) => little_endian,
+        };
+        Ok(Self {
+            kind,
+            width,
+            little_endian,
+        })
+    }
+}
+
+impl fmt::Display for NumberType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            NumberKind::Unsigned => "u",
+            NumberKind::Signed => "i",
+            NumberKind::Float => "f",
+        };
+        let order = match (self.width, self.little_endian) {
+            (1, _) => "",
+            (_, true) => "le",
+            (_, false) => "be",
+        };
+        write!(f, "{}{}{}", kind, self.width * 8, order)
+    }
+}
+
+// Encodes a JSON value as CBOR (RFC 8949), integers and floats using their shortest lossless
+// form.
+pub fn cbor(value: &Value) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    ciborium::ser::into_writer(value, &mut out).map_err(|err| err.to_string())?;
+    Ok(out)
+}
+
+// Encodes a JSON value as MessagePack, integers using their shortest form and other numbers as
+// double precision floats.
+pub fn msgpack(value: &Value) -> Result<Vec<u8>, String> {
+    rmp_serde::to_vec(value).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Number, Value};
+
+    use super::{cbor, msgpack, NumberType};
+
+    fn encode(number_type: &str, number: Number) -> Option<Vec<u8>> {
+        number_type
+            .parse::<NumberType>()
+            .unwrap()
+            .encode(&number)
+            .ok()
+    }
+
+    #[test]
+    fn number_types() {
+        for name in ["u8", "i8", "u16le", "i32be", "f32le", "f64be", "u64le"] {
+            assert_eq!(name.parse::<NumberType>().unwrap().to_string(), name);
+        }
+        for name in ["u16", "f32", "u8le", "f16le", "u24be", "x8", ""] {
+            assert!(name.parse::<NumberType>().is_err());
+        }
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(encode("u16le", 0x1234.into()), Some(vec![0x34, 0x12]));
+        assert_eq!(encode("u16be", 0x1234.into()), Some(vec![0x12, 0x34]));
+        assert_eq!(encode("i16le", (-2).into()), Some(vec![0xfe, 0xff]));
+        assert_eq!(encode("i8", (-128).into()), Some(vec![0x80]));
+        assert_eq!(
+            encode("f32be", Number::from_f64(1.5).unwrap()),
+            Some(vec![0x3f, 0xc0, 0, 0])
+        );
+        assert_eq!(encode("u64be", u64::MAX.into()), Some(vec![0xff; 8]));
+        assert_eq!(encode("u8", 256.into()), None);
+        assert_eq!(encode("u8", (-1).into()), None);
+        assert_eq!(encode("i8", 128.into()), None);
+        assert_eq!(encode("u32le", Number::from_f64(1.5).unwrap()), None);
+        assert_eq!(encode("f32le", Number::from_f64(1e300).unwrap()), None);
+    }
+
+    #[test]
+    fn decode_numbers() {
+        let decode = |name: &str, bytes: &[u8]| name.parse::<NumberType>().unwrap().decode(bytes);
+        assert_eq!(decode("u16le", &[0x34, 0x12]), Some(0x1234.into()));
+        assert_eq!(decode("i16be", &[0xff, 0xfe]), Some((-2).into()));
+        assert_eq!(decode("i8", &[0x80]), Some((-128).into()));
+        assert_eq!(decode("f32le", &[0, 0, 0xc0, 0x3f]), Number::from_f64(1.5));
+        assert_eq!(decode("u32le", &[0, 0]), None);
+    }
+
+    #[test]
+    fn cbor_values() {
+        assert_eq!(
+            cbor(&json!({"a": [1, -1, 1000], "b": null, "c": true})).unwrap(),
+            [
+                0xa3, 0x61, b'a', 0x83, 0x01, 0x20, 0x19, 0x03, 0xe8, 0x61, b'b', 0xf6, 0x61, b'c',
+                0xf5,
+            ]
+        );
+        assert_eq!(cbor(&json!(1.5)).unwrap(), [0xf9, 0x3e, 0x00]);
+        assert_eq!(cbor(&json!(0.1)).unwrap()[0], 0xfb);
+    }
+
+    #[test]
+    fn msgpack_values() {
+        assert_eq!(
+            msgpack(&json!({"a": [1, -1, 200, -200], "b": "hi", "c": false})).unwrap(),
+            [
+                0x83, 0xa1, b'a', 0x94, 0x01, 0xff, 0xcc, 0xc8, 0xd1, 0xff, 0x38, 0xa1, b'b', 0xa2,
+                b'h', b'i', 0xa1, b'c', 0xc2,
+            ]
+        );
+        assert_eq!(msgpack(&json!("x".repeat(40))).unwrap()[..2], [0xd9, 40]);
+    }
+
+    #[test]
+    fn round_trips() {
+        let value = json!({
+            "numbers": [0, 24, -25, u64::MAX, i64::MIN, 1.5, -0.1],
+            "nested": {"empty": [], "text": "x".repeat(300)},
+            "flags": [true, false, null],
+        });
+        let decoded: Value = ciborium::de::from_reader(&cbor(&value).unwrap()[..]).unwrap();
+        assert_eq!(decoded, value);
+        let decoded: Value = rmp_serde::from_slice(&msgpack(&value).unwrap()).unwrap();
+        assert_eq!(decoded, value);
+    }
+}
@@ -1,62 +1,376 @@
+use std::{error::Error as StdError, fmt};
+
 use axum::{
-    http::StatusCode,
+    http::{header, HeaderValue, Request, StatusCode},
+    middleware::Next,
     response::{IntoResponse, Response},
+    Json,
 };
+use serde::Serialize;
 use thiserror::Error;
 
+use crate::misc::header_str;
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("invalid mqtt client information")]
     ClientInformation,
     #[error("broker connection failed")]
     BrokerConnection,
+    #[error("broker rejected credentials")]
+    BrokerCredentials,
+    #[error("not authorized by broker")]
+    BrokerNotAuthorized,
+    #[error("broker unavailable")]
+    BrokerUnavailable,
     #[error("topic subscription failed")]
     Subscription,
+    #[error("topic subscription denied by broker")]
+    SubscriptionDenied,
     #[error("no message received before timeout")]
     PublishTimeout,
     #[error("message reception failed")]
     MessageReception,
     #[error("invalid message payload")]
     Payload,
+    #[error("unknown payload type")]
+    PayloadType,
+    #[error("invalid payload layout")]
+    Layout,
     #[error("publish failed")]
     Publish,
-    #[error("disconnection failure")]
-    Disconnect,
     #[error("missing or invalid header")]
     Header,
+    #[error("missing or invalid query parameter")]
+    QueryParameter,
     #[error("invalid broker url")]
     BrokerUrl,
+    #[error("broker not allowed")]
+    BrokerForbidden,
+    #[error("topic {0} not allowed")]
+    TopicForbidden(String),
+    #[error("unknown broker profile")]
+    Profile,
     #[error("invalid json format or payload too large")]
     JsonFormat,
     #[error("body too large")]
     BodySize,
+    #[error("failed to read body")]
+    Body,
     #[error("invalid topic path")]
     Topic,
+    #[error("invalid tls options")]
+    Tls,
+    #[error("invalid subscribe options")]
+    SubscribeOptions,
+    #[error("invalid message properties")]
+    Properties,
+    #[error("message properties require mqtt v5")]
+    PropertiesVersion,
+    #[error("broker deadline exceeded")]
+    BrokerTimeout,
+    #[error("missing or invalid token")]
+    Unauthorized,
+    #[error("websocket disabled")]
+    WebSocketDisabled,
+    #[error("publish disabled")]
+    PublishDisabled,
+    // Another error, with its cause and location.
+    #[error("{error}{details}")]
+    Detailed {
+        error: Box<Error>,
+        details: Box<Details>,
+    },
 }
 
 impl Error {
-    fn status_code(&self) -> StatusCode {
+    pub(crate) fn status_code(&self) -> StatusCode {
         use Error::*;
         match self {
             ClientInformation => StatusCode::BAD_REQUEST,
             BrokerConnection => StatusCode::BAD_GATEWAY,
+            // Not 401, which is httq's own authentication failure.
+            BrokerCredentials => StatusCode::BAD_GATEWAY,
+            BrokerNotAuthorized => StatusCode::FORBIDDEN,
+            BrokerUnavailable => StatusCode::SERVICE_UNAVAILABLE,
             Subscription => StatusCode::BAD_GATEWAY,
+            SubscriptionDenied => StatusCode::FORBIDDEN,
             PublishTimeout => StatusCode::GATEWAY_TIMEOUT,
             MessageReception => StatusCode::BAD_GATEWAY,
             Payload => StatusCode::BAD_REQUEST,
+            PayloadType => StatusCode::BAD_REQUEST,
+            Layout => StatusCode::BAD_REQUEST,
             Publish => StatusCode::BAD_GATEWAY,
-            Disconnect => StatusCode::BAD_GATEWAY,
             Header => StatusCode::BAD_REQUEST,
+            QueryParameter => StatusCode::BAD_REQUEST,
             BrokerUrl => StatusCode::BAD_REQUEST,
+            BrokerForbidden => StatusCode::FORBIDDEN,
+            TopicForbidden(_) => StatusCode::FORBIDDEN,
+            Profile => StatusCode::BAD_REQUEST,
             JsonFormat => StatusCode::BAD_REQUEST,
             BodySize => StatusCode::PAYLOAD_TOO_LARGE,
+            Body => StatusCode::BAD_REQUEST,
             Topic => StatusCode::BAD_REQUEST,
+            Tls => StatusCode::BAD_REQUEST,
+            SubscribeOptions => StatusCode::BAD_REQUEST,
+            Properties => StatusCode::BAD_REQUEST,
+            PropertiesVersion => StatusCode::BAD_REQUEST,
+            BrokerTimeout => StatusCode::GATEWAY_TIMEOUT,
+            Unauthorized => StatusCode::UNAUTHORIZED,
+            WebSocketDisabled => StatusCode::NOT_FOUND,
+            PublishDisabled => StatusCode::FORBIDDEN,
+            Detailed { error, .. } => error.status_code(),
+        }
+    }
+
+    // Identifies the error in problem details.
+    fn code(&self) -> &'static str {
+        use Error::*;
+        match self {
+            ClientInformation => "client-information",
+            BrokerConnection => "broker-connection",
+            BrokerCredentials => "broker-credentials",
+            BrokerNotAuthorized => "broker-not-authorized",
+            BrokerUnavailable => "broker-unavailable",
+            Subscription => "subscription",
+            SubscriptionDenied => "subscription-denied",
+            PublishTimeout => "publish-timeout",
+            MessageReception => "message-reception",
+            Payload => "payload",
+            PayloadType => "payload-type",
+            Layout => "layout",
+            Publish => "publish",
+            Header => "header",
+            QueryParameter => "query-parameter",
+            BrokerUrl => "broker-url",
+            BrokerForbidden => "broker-forbidden",
+            TopicForbidden(_) => "topic-forbidden",
+            Profile => "profile",
+            JsonFormat => "json-format",
+            BodySize => "body-size",
+            Body => "body",
+            Topic => "topic",
+            Tls => "tls",
+            SubscribeOptions => "subscribe-options",
+            Properties => "properties",
+            PropertiesVersion => "properties-version",
+            BrokerTimeout => "broker-timeout",
+            Unauthorized => "unauthorized",
+            WebSocketDisabled => "websocket-disabled",
+            PublishDisabled => "publish-disabled",
+            Detailed { error, .. } => error.code(),
+        }
+    }
+
+    // The error without its details.
+    pub fn kind(&self) -> &Self {
+        match self {
+            Self::Detailed { error, .. } => error,
+            error => error,
+        }
+    }
+
+    fn details(&self) -> Option<&Details> {
+        match self {
+            Self::Detailed { details, .. } => Some(details),
+            _ => None,
+        }
+    }
+
+    fn with_details(self, update: impl FnOnce(&mut Details)) -> Self {
+        let (error, mut details) = match self {
+            Self::Detailed { error, details } => (error, details),
+            error => (Box::new(error), Box::default()),
+        };
+        update(&mut details);
+        Self::Detailed { error, details }
+    }
+
+    pub fn because(self, source: impl Into<Box<dyn StdError + Send + Sync>>) -> Self {
+        self.with_details(|details| details.source = Some(source.into()))
+    }
+
+    // A JSON path, header or query parameter.
+    pub fn field(self, field: impl Into<String>) -> Self {
+        self.with_details(|details| details.field = Some(field.into()))
+    }
+
+    pub fn broker(self, broker: impl Into<String>) -> Self {
+        self.with_details(|details| details.broker = Some(broker.into()))
+    }
+
+    pub fn message(self, index: usize) -> Self {
+        self.with_details(|details| details.message_index = Some(index))
+    }
+
+    fn problem(&self) -> Problem {
+        let details = self.details();
+        Problem {
+            kind: format!("urn:httq:error:{}", self.code()),
+            title: self.kind().to_string(),
+            status: self.status_code().as_u16(),
+            detail: details
+                .and_then(|details| details.source.as_ref())
+                .map(ToString::to_string),
+            field: details.and_then(|details| details.field.clone()),
+            broker: details.and_then(|details| details.broker.clone()),
+            message_index: details.and_then(|details| details.message_index),
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct Details {
+    source: Option<Box<dyn StdError + Send + Sync>>,
+    field: Option<String>,
+    broker: Option<String>,
+    message_index: Option<usize>,
+}
+
+// Only the cause and field, as errors are reported with their broker and message elsewhere.
+impl fmt::Display for Details {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(field) = &self.field {
+            write!(f, ": {}", field)?;
         }
+        if let Some(source) = &self.source {
+            write!(f, ": {}", source)?;
+        }
+        Ok(())
     }
 }
 
+// RFC 7807 problem details.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Problem {
+    #[serde(rename = "type")]
+    kind: String,
+    title: String,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    broker: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_index: Option<usize>,
+}
+
+// The plain text rendering of an error response, used when requested by the client.
+#[derive(Clone)]
+struct PlainText(String);
+
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
-        (self.status_code(), self.to_string()).into_response()
+        let mut response = (
+            self.status_code(),
+            [(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/problem+json"),
+            )],
+            Json(self.problem()),
+        )
+            .into_response();
+        if let Self::Unauthorized = self.kind() {
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        response
+            .extensions_mut()
+            .insert(PlainText(self.to_string()));
+        response
+    }
+}
+
+// Renders errors as plain text for clients accepting it but not JSON.
+pub async fn negotiate<B>(req: Request<B>, next: Next<B>) -> Response {
+    let plain_text = matches!(
+        header_str(req.headers(), header::ACCEPT),
+        Some(accept) if accept.contains("text/plain") && !accept.contains("json")
+    );
+    let mut response = next.run(req).await;
+    match response.extensions_mut().remove::<PlainText>() {
+        Some(PlainText(text)) if plain_text => {
+            let mut plain = (response.status(), text).into_response();
+            if let Some(challenge) = response.headers_mut().remove(header::WWW_AUTHENTICATE) {
+                plain
+                    .headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, challenge);
+            }
+            plain
+        }
+        _ => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::HttpBody, http::StatusCode, response::IntoResponse};
+    use serde_json::{json, Value};
+
+    use super::Error;
+
+    #[test]
+    fn details() {
+        let error = Error::JsonFormat
+            .because("invalid type: string \"1\", expected i32 at line 4 column 20")
+            .field("messages[2].qos")
+            .broker("tcp://broker.com");
+        assert!(matches!(error.kind(), Error::JsonFormat));
+        assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            error.to_string(),
+            "invalid json format or payload too large: messages[2].qos: invalid type: string \"1\", \
+             expected i32 at line 4 column 20"
+        );
+    }
+
+    #[tokio::test]
+    async fn problem_json() {
+        let response = Error::Publish
+            .because("[-3] Disconnected")
+            .broker("tcp://broker.com")
+            .message(1)
+            .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            response.headers()["content-type"],
+            "application/problem+json"
+        );
+        let body = response.into_body().data().await.unwrap().unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&body).unwrap(),
+            json!({
+                "type": "urn:httq:error:publish",
+                "title": "publish failed",
+                "status": 502,
+                "detail": "[-3] Disconnected",
+                "broker": "tcp://broker.com",
+                "messageIndex": 1,
+            })
+        );
+    }
+
+    #[test]
+    fn broker_credentials() {
+        let response = Error::BrokerCredentials.into_response();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert!(!response.headers().contains_key("www-authenticate"));
+        assert_ne!(
+            Error::BrokerCredentials.problem().kind,
+            Error::Unauthorized.problem().kind
+        );
+    }
+
+    #[test]
+    fn unauthorized() {
+        let response = Error::Unauthorized.because("invalid token").into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()["www-authenticate"], "Bearer");
+        let response = Error::Tls.into_response();
+        assert!(!response.headers().contains_key("www-authenticate"));
     }
 }
@@ -0,0 +1,336 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    mem,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures_util::StreamExt;
+use paho_mqtt::{AsyncClient, Message};
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        Mutex as AsyncMutex,
+    },
+    task::JoinHandle,
+    time::sleep,
+};
+
+use crate::{client, config::Config, connect_info::ConnectInfo, Error};
+
+const STREAM_BUFFER_SIZE: usize = 64;
+const BROADCAST_BUFFER_SIZE: usize = 64;
+const HISTORY_SIZE: usize = 256;
+
+#[derive(Hash, PartialEq, Eq, Clone)]
+struct Key {
+    connect_info: ConnectInfo,
+    topic: String,
+    qos: i32,
+}
+
+type Slot = Arc<AsyncMutex<Weak<Subscription>>>;
+
+// Holds a single broker subscription per topic filter, shared by every HTTP request waiting on it.
+pub struct Hub {
+    slots: Mutex<HashMap<Key, Slot>>,
+}
+
+impl Hub {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            slots: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub async fn subscribe(
+        self: &Arc<Self>,
+        connect_info: ConnectInfo,
+        topic: String,
+        qos: i32,
+        resume_from: Option<&str>,
+        config: &Config,
+    ) -> Result<Subscriber, Error> {
+        let key = Key {
+            connect_info,
+            topic,
+            qos,
+        };
+        let slot = Arc::clone(self.slots.lock().unwrap().entry(key.clone()).or_default());
+
+        let mut current = slot.lock().await;
+        if let Some(subscriber) = current
+            .upgrade()
+            .and_then(|subscription| subscription.subscriber(resume_from))
+        {
+            return Ok(subscriber);
+        }
+
+        let subscription = match Subscription::open(Arc::downgrade(self), key, config).await {
+            Ok(subscription) => subscription,
+            Err(err) => {
+                drop(current);
+                self.release(&slot);
+                return Err(err);
+            }
+        };
+        *current = Arc::downgrade(&subscription);
+        subscription
+            .subscriber(resume_from)
+            .ok_or(Error::Subscription)
+    }
+
+    // Removes the slot from the hub if no request is using or about to use it.
+    fn release(&self, slot: &Slot) {
+        let mut slots = self.slots.lock().unwrap();
+        let unused = Arc::strong_count(slot) == 2
+            && slot
+                .try_lock()
+                .map(|current| current.strong_count() == 0)
+                .unwrap_or(false);
+        if unused {
+            slots.retain(|_, other| !Arc::ptr_eq(other, slot));
+        }
+    }
+}
+
+struct Subscription {
+    hub: Weak<Hub>,
+    key: Key,
+    // Identifies this subscription instance in message ids, so a client can only resume from a
+    // message received by the same subscription.
+    generation: u128,
+    client: AsyncClient,
+    forwarder: JoinHandle<()>,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    // Cleared when the connection is lost, so waiting subscribers are notified and new ones open
+    // a fresh subscription.
+    sender: Option<broadcast::Sender<(u64, Message)>>,
+    // Retained messages are only sent by the broker when subscribing, so they are replayed to the
+    // requests joining an already opened subscription.
+    retained: HashMap<String, (u64, Message)>,
+    history: VecDeque<(u64, Message)>,
+    next_sequence: u64,
+}
+
+impl Subscription {
+    async fn open(hub: Weak<Hub>, key: Key, config: &Config) -> Result<Arc<Self>, Error> {
+        let mut client = client::create(&key.connect_info, config)?;
+        let mut stream = client.get_stream(STREAM_BUFFER_SIZE);
+        client::connect(&client, key.connect_info.clone(), config).await?;
+        let response = client
+            .subscribe(key.topic.as_str(), key.qos)
+            .await
+            .map_err(|err| client::subscribe_error(&err).because(err))?;
+        // A failure granted in the SUBACK, in case the client library didn't report it as an error.
+        if response.subscribe_response() == Some(0x80) || response.reason_code().is_err() {
+            return Err(Error::SubscriptionDenied);
+        }
+
+        let state = Arc::new(Mutex::new(State {
+            sender: Some(broadcast::channel(BROADCAST_BUFFER_SIZE).0),
+            retained: HashMap::new(),
+            history: VecDeque::with_capacity(HISTORY_SIZE),
+            next_sequence: 0,
+        }));
+        let forwarder = tokio::spawn({
+            let state = Arc::clone(&state);
+            async move {
+                while let Some(Some(message)) = stream.next().await {
+                    state.lock().unwrap().dispatch(message);
+                }
+                state.lock().unwrap().sender = None;
+            }
+        });
+
+        Ok(Arc::new(Self {
+            hub,
+            key,
+            generation: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+            client,
+            forwarder,
+            state,
+        }))
+    }
+
+    fn subscriber(self: Arc<Self>, resume_from: Option<&str>) -> Option<Subscriber> {
+        let (pending, receiver) = {
+            let state = self.state.lock().unwrap();
+            let pending = match resume_from.and_then(|id| self.parse_id(id)) {
+                Some(last) => state
+                    .history
+                    .iter()
+                    .filter(|(sequence, _)| *sequence > last)
+                    .cloned()
+                    .collect(),
+                None => {
+                    let mut retained = state.retained.values().cloned().collect::<Vec<_>>();
+                    retained.sort_by_key(|(sequence, _)| *sequence);
+                    retained.into()
+                }
+            };
+            (pending, state.sender.as_ref()?.subscribe())
+        };
+        Some(Subscriber {
+            pending,
+            receiver,
+            last_sequence: None,
+            lost: 0,
+            linger: None,
+            subscription: self,
+        })
+    }
+
+    // Returns the sequence number of a message id, if it was produced by this subscription.
+    fn parse_id(&self, id: &str) -> Option<u64> {
+        let (generation, sequence) = id.split_once('-')?;
+        if generation.parse::<u128>().ok()? != self.generation {
+            return None;
+        }
+        sequence.parse().ok()
+    }
+}
+
+impl State {
+    fn dispatch(&mut self, message: Message) {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        // Live messages are delivered with the retain flag unset, whether the publisher retained
+        // them or not (empty clearing messages included), so they replace the topic's cached
+        // retained message, or remove it when empty.
+        if message.retained() || self.retained.contains_key(message.topic()) {
+            if message.payload().is_empty() {
+                self.retained.remove(message.topic());
+            } else {
+                self.retained
+                    .insert(message.topic().to_owned(), (sequence, message.clone()));
+            }
+        }
+        if self.history.len() == HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back((sequence, message.clone()));
+        if let Some(sender) = &self.sender {
+            let _ = sender.send((sequence, message));
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.forwarder.abort();
+        // The disconnection is started by the C library, the token only reports its completion.
+        drop(self.client.disconnect(None));
+        if let Some(hub) = self.hub.upgrade() {
+            let slot = hub.slots.lock().unwrap().get(&self.key).cloned();
+            if let Some(slot) = slot {
+                hub.release(&slot);
+            }
+        }
+    }
+}
+
+pub struct Subscriber {
+    pending: VecDeque<(u64, Message)>,
+    receiver: broadcast::Receiver<(u64, Message)>,
+    last_sequence: Option<u64>,
+    // Messages dropped since last taken, as this subscriber didn't keep up with the broadcast.
+    lost: u64,
+    linger: Option<Duration>,
+    subscription: Arc<Subscription>,
+}
+
+impl Subscriber {
+    pub async fn recv(&mut self) -> Option<Message> {
+        let (sequence, message) = match self.pending.pop_front() {
+            Some(pending) => pending,
+            None => loop {
+                match self.receiver.recv().await {
+                    // Messages already replayed from the history.
+                    Ok((sequence, _)) if Some(sequence) <= self.last_sequence => continue,
+                    Ok(received) => break received,
+                    Err(RecvError::Lagged(count)) => self.lost += count,
+                    Err(RecvError::Closed) => return None,
+                }
+            },
+        };
+        self.last_sequence = Some(sequence);
+        Some(message)
+    }
+
+    // Number of messages lost since the last call, to be reported to the client.
+    pub fn take_lost(&mut self) -> u64 {
+        mem::take(&mut self.lost)
+    }
+
+    // Id of the last received message, that can be used to resume the subscription later on.
+    pub fn last_id(&self) -> Option<String> {
+        Some(format!(
+            "{}-{}",
+            self.subscription.generation, self.last_sequence?
+        ))
+    }
+
+    // Keeps the upstream subscription open for a while after this subscriber leaves, so a client
+    // can reconnect and resume without missing messages.
+    pub fn set_linger(&mut self, duration: Duration) {
+        self.linger = Some(duration);
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        if let Some(duration) = self.linger {
+            let subscription = Arc::clone(&self.subscription);
+            tokio::spawn(async move {
+                sleep(duration).await;
+                drop(subscription);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, VecDeque};
+
+    use paho_mqtt::{Message, MessageBuilder};
+
+    use super::State;
+
+    fn retained(topic: &str, payload: &str) -> Message {
+        MessageBuilder::new()
+            .topic(topic)
+            .payload(payload)
+            .retained(true)
+            .finalize()
+    }
+
+    #[test]
+    fn retained_messages() {
+        let mut state = State {
+            sender: None,
+            retained: HashMap::new(),
+            history: VecDeque::new(),
+            next_sequence: 0,
+        };
+        state.dispatch(retained("door", "open"));
+        state.dispatch(Message::new("window", "open", 0));
+        assert!(!state.retained.contains_key("window"));
+        state.dispatch(Message::new("door", "closed", 0));
+        assert_eq!(state.retained["door"].1.payload_str(), "closed");
+        state.dispatch(Message::new("door", "", 0));
+        assert!(state.retained.is_empty());
+        state.dispatch(retained("door", "open"));
+        state.dispatch(retained("door", ""));
+        assert!(state.retained.is_empty());
+        assert_eq!(state.history.len(), 6);
+    }
+}
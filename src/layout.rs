@@ -0,0 +1,381 @@
+use std::{collections::HashMap, error::Error as StdError, fs, path::Path, str::FromStr};
+
+use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Value};
+
+use crate::{encoding::NumberType, Error};
+
+// The binary layout of a C struct, declared like `u8 cmd; u16le id; f32le value; char[8] tag`, or
+// `le {u8 cmd; u16 id; f32 value; char[8] tag}` to give every number the same byte order. Fields
+// are packed in order without implicit alignment, which is declared using `pad[N]`.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Layout {
+    fields: Vec<Field>,
+    // In bytes.
+    size: usize,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+enum Field {
+    Number(String, NumberType),
+    Array(String, NumberType, usize),
+    // A string, padded with NUL bytes.
+    Chars(String, usize),
+    Padding(usize),
+}
+
+impl Field {
+    fn name(&self) -> Option<&str> {
+        match self {
+            Self::Number(name, _) | Self::Array(name, _, _) | Self::Chars(name, _) => Some(name),
+            Self::Padding(_) => None,
+        }
+    }
+
+    // Can't overflow, as checked when parsing.
+    fn size(&self) -> usize {
+        match self {
+            Self::Number(_, number_type) => number_type.width(),
+            Self::Array(_, number_type, len) => number_type.width() * len,
+            Self::Chars(_, len) | Self::Padding(len) => *len,
+        }
+    }
+
+    fn pack(&self, value: Option<&Value>, out: &mut Vec<u8>) -> Result<(), String> {
+        let number = |value: Option<&Value>, number_type: &NumberType| match value {
+            Some(Value::Number(number)) => number_type.encode(number),
+            Some(_) => Err("expected a number".to_owned()),
+            None => Err("missing value".to_owned()),
+        };
+        match self {
+            Self::Number(_, number_type) => out.extend(number(value, number_type)?),
+            Self::Array(_, number_type, len) => match value {
+                Some(Value::Array(values)) if values.len() == *len => {
+                    for value in values {
+                        out.extend(number(Some(value), number_type)?);
+                    }
+                }
+                _ => return Err(format!("expected an array of {} numbers", len)),
+            },
+            Self::Chars(_, len) => match value {
+                Some(Value::String(s)) if s.len() <= *len => {
+                    out.extend(s.as_bytes());
+                    out.resize(out.len() + len - s.len(), 0);
+                }
+                _ => return Err(format!("expected a string of up to {} bytes", len)),
+            },
+            Self::Padding(len) => out.resize(out.len() + len, 0),
+        }
+        Ok(())
+    }
+
+    fn unpack(&self, bytes: &[u8]) -> Value {
+        let number = |number_type: &NumberType, bytes| {
+            number_type
+                .decode(bytes)
+                .map(Value::Number)
+                .unwrap_or_default()
+        };
+        match self {
+            Self::Number(_, number_type) => number(number_type, bytes),
+            Self::Array(_, number_type, _) => bytes
+                .chunks(number_type.width())
+                .map(|bytes| number(number_type, bytes))
+                .collect(),
+            Self::Chars(_, _) => {
+                let end = bytes.iter().position(|byte| *byte == 0);
+                String::from_utf8_lossy(&bytes[..end.unwrap_or(bytes.len())]).into()
+            }
+            Self::Padding(_) => Value::Null,
+        }
+    }
+}
+
+impl Layout {
+    // Every field of the layout must be given a value, and every value must match a field.
+    pub fn pack(&self, values: &Map<String, Value>) -> Result<Vec<u8>, Error> {
+        if let Some(name) = values
+            .keys()
+            .find(|name| !self.fields.iter().any(|field| field.name() == Some(name)))
+        {
+            return Err(Error::Payload.because(format!("unknown struct field `{}`", name)));
+        }
+        let mut out = Vec::with_capacity(self.size());
+        for field in &self.fields {
+            let value = field.name().and_then(|name| values.get(name));
+            field.pack(value, &mut out).map_err(|err| {
+                Error::Payload.because(format!(
+                    "struct field `{}`: {}",
+                    field.name().unwrap_or_default(),
+                    err
+                ))
+            })?;
+        }
+        Ok(out)
+    }
+
+    // Payloads of another size than the layout's can't be decoded.
+    pub fn unpack(&self, bytes: &[u8]) -> Option<Map<String, Value>> {
+        if bytes.len() != self.size() {
+            return None;
+        }
+        let mut offset = 0;
+        let mut values = Map::new();
+        for field in &self.fields {
+            let bytes = &bytes[offset..offset + field.size()];
+            offset += field.size();
+            if let Some(name) = field.name() {
+                values.insert(name.to_owned(), field.unpack(bytes));
+            }
+        }
+        Some(values)
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    // Declarations are `type name`, `type[N] name` or `pad[N]`, separated by semicolons and
+    // optionally enclosed in braces, which a default byte order may precede.
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let input = input.trim();
+        let (order, input) = match (input.get(..2), input.get(2..).map(str::trim_start)) {
+            (Some(order @ ("le" | "be")), Some(rest)) if rest.starts_with('{') => {
+                (Some(order), rest)
+            }
+            _ => (None, input),
+        };
+        let input = input
+            .strip_prefix('{')
+            .and_then(|input| input.strip_suffix('}'))
+            .unwrap_or(input);
+        let mut fields: Vec<Field> = Vec::new();
+        let mut size: usize = 0;
+        for declaration in input.split(';').map(str::trim) {
+            if declaration.is_empty() {
+                continue;
+            }
+            let invalid = || format!("invalid field declaration `{}`", declaration);
+            let mut words = declaration.split_whitespace();
+            let (field_type, name) = (words.next().ok_or_else(invalid)?, words.next());
+            if words.next().is_some() {
+                return Err(invalid());
+            }
+            let (field_type, len) = match field_type.split_once('[') {
+                Some((field_type, len)) => {
+                    let len = len
+                        .strip_suffix(']')
+                        .and_then(|len| len.parse().ok())
+                        .filter(|len| *len > 0)
+                        .ok_or_else(invalid)?;
+                    (field_type, Some(len))
+                }
+                None => (field_type, None),
+            };
+            let name = name.map(str::to_owned);
+            let field = match (field_type, len, name) {
+                ("pad", Some(len), None) => Field::Padding(len),
+                ("char", Some(len), Some(name)) => Field::Chars(name, len),
+                (number_type, len, Some(name)) => {
+                    let number_type = number_type
+                        .parse()
+                        .or_else(|_| match order {
+                            Some(order) => format!("{}{}", number_type, order).parse(),
+                            None => Err(()),
+                        })
+                        .map_err(|_| invalid())?;
+                    match len {
+                        Some(len) => Field::Array(name, number_type, len),
+                        None => Field::Number(name, number_type),
+                    }
+                }
+                _ => return Err(invalid()),
+            };
+            if field.name().is_some() && fields.iter().any(|other| other.name() == field.name()) {
+                return Err(format!(
+                    "duplicate field `{}`",
+                    field.name().unwrap_or_default()
+                ));
+            }
+            let field_size = match &field {
+                Field::Array(_, number_type, len) => number_type.width().checked_mul(*len),
+                field => Some(field.size()),
+            };
+            size = field_size
+                .and_then(|field_size| size.checked_add(field_size))
+                .ok_or_else(|| "layout too large".to_owned())?;
+            fields.push(field);
+        }
+        if fields.is_empty() {
+            return Err("empty layout".to_owned());
+        }
+        Ok(Self { fields, size })
+    }
+}
+
+impl<'de> Deserialize<'de> for Layout {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+// Layouts declared server-side, referenced by requests as `@name`.
+#[derive(Deserialize, Default)]
+#[serde(transparent)]
+pub struct Layouts(HashMap<String, Layout>);
+
+impl Layouts {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn StdError + Send + Sync>> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    // Looks up a server-side layout, or parses one declared by the request. Layouts can't be
+    // larger than `max_size`, as packing one allocates its whole size.
+    pub fn resolve(&self, layout: &str, max_size: usize) -> Result<Layout, Error> {
+        let layout = match layout.strip_prefix('@') {
+            Some(name) => self
+                .0
+                .get(name)
+                .cloned()
+                .ok_or_else(|| Error::Layout.because(format!("unknown layout {}", name))),
+            None => layout
+                .parse()
+                .map_err(|err: String| Error::Layout.because(err)),
+        }?;
+        if layout.size() > max_size {
+            return Err(Error::Layout.because(format!("layout larger than {} bytes", max_size)));
+        }
+        Ok(layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::{Layout, Layouts};
+    use crate::Error;
+
+    const COMMAND: &str = "{u8 cmd; u16le id; f32le value; char[8] tag}";
+
+    fn values(json: Value) -> serde_json::Map<String, Value> {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn pack() {
+        let layout: Layout = COMMAND.parse().unwrap();
+        let packed = layout
+            .pack(&values(
+                json!({"cmd": 1, "id": 513, "value": 1.5, "tag": "door"}),
+            ))
+            .unwrap();
+        assert_eq!(
+            packed,
+            [1, 1, 2, 0, 0, 0xc0, 0x3f, b'd', b'o', b'o', b'r', 0, 0, 0, 0]
+        );
+
+        let layout: Layout = "u8 flags; pad[3]; i16be[2] samples".parse().unwrap();
+        assert_eq!(
+            layout
+                .pack(&values(json!({"flags": 3, "samples": [-1, 2]})))
+                .unwrap(),
+            [3, 0, 0, 0, 0xff, 0xff, 0, 2]
+        );
+    }
+
+    #[test]
+    fn invalid_values() {
+        let layout: Layout = COMMAND.parse().unwrap();
+        for json in [
+            json!({"cmd": 1, "id": 1, "value": 1}),
+            json!({"cmd": 256, "id": 1, "value": 1, "tag": ""}),
+            json!({"cmd": 1, "id": 1, "value": 1, "tag": "too long tag"}),
+            json!({"cmd": 1, "id": 1, "value": 1, "tag": "", "extra": 0}),
+        ] {
+            assert!(matches!(
+                layout.pack(&values(json)),
+                Err(Error::Detailed { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn unpack() {
+        let layout: Layout = COMMAND.parse().unwrap();
+        assert_eq!(
+            Value::Object(
+                layout
+                    .unpack(&[1, 1, 2, 0, 0, 0xc0, 0x3f, b'd', b'o', b'o', b'r', 0, 0, 0, 0])
+                    .unwrap()
+            ),
+            json!({"cmd": 1, "id": 513, "value": 1.5, "tag": "door"})
+        );
+        assert_eq!(layout.unpack(&[1, 2, 3]), None);
+    }
+
+    #[test]
+    fn byte_order() {
+        assert_eq!(
+            "le {u8 cmd; u16 id; f32 value; char[8] tag}".parse::<Layout>(),
+            COMMAND.parse()
+        );
+        assert_eq!(
+            "be{u16 id; u16le flags}".parse::<Layout>(),
+            "u16be id; u16le flags".parse()
+        );
+    }
+
+    #[test]
+    fn invalid_layouts() {
+        for layout in [
+            "",
+            "u8",
+            "u16 id",
+            "u8 a; u8 a",
+            "char tag",
+            "char[0] tag",
+            "pad[2] name",
+            "u8 a b",
+            "be u16 id",
+            "u64le[3000000000000000000] x",
+            "pad[18446744073709551615]; u8 x",
+        ] {
+            assert!(layout.parse::<Layout>().is_err(), "{}", layout);
+        }
+    }
+
+    #[test]
+    fn server_layouts() {
+        let layouts: Layouts = toml::from_str(&format!("command = \"{}\"", COMMAND)).unwrap();
+        assert_eq!(
+            layouts.resolve("@command", 1024).unwrap(),
+            COMMAND.parse().unwrap()
+        );
+        assert!(layouts.resolve("@unknown", 1024).is_err());
+        assert!(layouts.resolve("u8 cmd", 1024).is_ok());
+        assert!(layouts.resolve("@command", 8).is_err());
+    }
+
+    #[test]
+    fn max_size() {
+        let layouts = Layouts::default();
+        assert!(matches!(
+            layouts
+                .resolve("pad[100000000000]", 1024)
+                .as_ref()
+                .map_err(Error::kind),
+            Err(Error::Layout)
+        ));
+        assert!(layouts.resolve("pad[1024]", 1024).is_ok());
+    }
+}
@@ -1,127 +1,298 @@
-use std::{error::Error as StdError, net::SocketAddr, time::Duration};
+use std::{env, error::Error as StdError, net::SocketAddr, sync::Arc};
 
 use axum::{
-    extract::DefaultBodyLimit,
-    http::{header, header::HeaderName, HeaderMap, StatusCode},
+    extract::{ws::WebSocketUpgrade, DefaultBodyLimit, State},
+    http::{header, header::HeaderName, HeaderMap, Request, StatusCode, Uri},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::post,
-    Router, Server,
+    routing::{get, MethodRouter},
+    Json, Router, Server,
 };
-use futures_util::StreamExt;
-use paho_mqtt::{
-    AsyncClient, ConnectOptions, ConnectOptionsBuilder, CreateOptionsBuilder, Message, QOS_2,
-};
-use tokio::time::timeout;
+use futures_util::future;
+use log::LevelFilter;
+use serde_json::Value;
+use tokio::{runtime, time::timeout};
 
 use crate::{
-    connect_info::{ConnectInfo, Credentials, Topic},
+    acl::Access,
+    auth::Identity,
+    config::{Config, Settings},
+    connect_info::{ConnectInfo, QueryConnectInfo, Topic},
     error::Error,
-    misc::header_str,
-    publish::PublishRequest,
+    hub::Hub,
+    misc::{constant_time_eq, header_str, query_param},
+    pool::Pool,
+    properties::MessageProperties,
+    publish::{Broker, Message, MessageGroup, PublishOptions, PublishRequest},
+    report::PublishReport,
+    state::AppState,
+    subscribe::{collect, event_stream, SubscribeOptions},
 };
 
+mod acl;
+mod auth;
+mod client;
+mod config;
 mod connect_info;
+mod encoding;
 mod error;
+mod hub;
+mod layout;
 mod misc;
+mod policy;
+mod pool;
+mod profile;
+mod properties;
 mod publish;
+mod report;
+mod state;
+mod subscribe;
+mod websocket;
 
-const MAX_PAYLOAD_SIZE: usize = 16_777_216;
+fn main() -> Result<(), Box<dyn StdError + Send + Sync>> {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        print!("{}", Settings::usage());
+        return Ok(());
+    }
+    let config = Arc::new(Config::load(&Settings::load(args)?)?);
+    // Dependencies only log warnings and errors.
+    env_logger::Builder::new()
+        .filter_level(config.log_level.min(LevelFilter::Warn))
+        .filter_module(module_path!(), config.log_level)
+        .init();
+    let runtime = match config.worker_threads {
+        1 => runtime::Builder::new_current_thread(),
+        threads => {
+            let mut builder = runtime::Builder::new_multi_thread();
+            builder.worker_threads(threads);
+            builder
+        }
+    }
+    .enable_all()
+    .build()?;
+    runtime.block_on(serve(config))
+}
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<(), Box<dyn StdError + Send + Sync>> {
-    Server::bind(&SocketAddr::new("0.0.0.0".parse()?, 8080))
-        .http1_title_case_headers(true)
-        .serve(
+async fn serve(config: Arc<Config>) -> Result<(), Box<dyn StdError + Send + Sync>> {
+    let state = AppState {
+        pool: Pool::new(config.pool_idle_timeout),
+        hub: Hub::new(),
+        config,
+    };
+    let mut route = MethodRouter::new();
+    let mut topic_route = MethodRouter::new();
+    if state.config.enable_publish {
+        route = route.post(publish_handler).delete(clear_handler);
+        topic_route = topic_route.post(publish_handler).delete(clear_handler);
+    }
+    if state.config.enable_subscribe {
+        route = route.get(subscribe_handler);
+        topic_route = topic_route.get(subscribe_handler);
+    }
+    let mut router = Router::new()
+        .route("/", route)
+        .route("/*topic", topic_route);
+    // Disabled by default, `/publish/...` then subscribes to the `publish/...` topics, which can
+    // otherwise only be subscribed to using `X-Topic` or `?topic=` (see the README).
+    if state.config.enable_publish && state.config.query_publish_token.is_some() {
+        router = router.nest(
+            "/publish",
             Router::new()
-                .route("/", post(publish_handler).get(subscribe_handler))
-                .route("/*topic", post(publish_handler).get(subscribe_handler))
-                .layer(DefaultBodyLimit::max(MAX_PAYLOAD_SIZE))
-                .into_make_service(),
-        )
-        .await?;
+                .route("/*topic", get(query_publish_handler))
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    check_query_token,
+                )),
+        );
+    }
+
+    let app = router
+        .layer(DefaultBodyLimit::max(state.config.max_body_size))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::middleware,
+        ))
+        .layer(middleware::from_fn(error::negotiate))
+        .with_state(state.clone())
+        .into_make_service();
+    let servers = state
+        .config
+        .bind
+        .iter()
+        .map(|ip| {
+            let addr = SocketAddr::new(*ip, state.config.port);
+            Server::try_bind(&addr)
+                .map(|server| server.http1_title_case_headers(true).serve(app.clone()))
+                .map_err(|err| format!("failed to bind {}: {}", addr, err))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    future::try_join_all(servers).await?;
     Ok(())
 }
 
-async fn publish_handler(req: PublishRequest) -> Result<StatusCode, Error> {
-    for broker in req {
-        let client = AsyncClient::new(
-            CreateOptionsBuilder::new()
-                .server_uri(broker.url)
-                .finalize(),
-        )
-        .map_err(|_| Error::ClientInformation)?;
-
-        let opts = match broker.credentials {
-            Some(Credentials { username, password }) => ConnectOptionsBuilder::new()
-                .user_name(username)
-                .password(password)
-                .finalize(),
-            None => ConnectOptions::new(),
-        };
-        client
-            .connect(opts)
-            .await
-            .map_err(|_| Error::BrokerConnection)?;
-
-        for message in broker.messages.into_iter() {
-            let (topic, qos) = (message.topic.clone(), message.qos);
-            let msg = Message::new(topic, message.payload().ok_or(Error::Payload)?, qos);
-            client.publish(msg).await.map_err(|_| Error::Publish)?;
-        }
+async fn publish_handler(
+    State(config): State<Arc<Config>>,
+    State(pool): State<Arc<Pool>>,
+    identity: Identity,
+    options: PublishOptions,
+    req: PublishRequest,
+) -> Result<PublishReport, Error> {
+    req.authorize(&identity, &config).await?;
+    Ok(req.publish(&pool, &config, &options).await)
+}
 
-        client
-            .disconnect(None)
-            .await
-            .map_err(|_| Error::Disconnect)?;
+async fn clear_handler(
+    State(config): State<Arc<Config>>,
+    State(pool): State<Arc<Pool>>,
+    identity: Identity,
+    connect_info: ConnectInfo,
+    Topic(topic): Topic,
+) -> Result<StatusCode, Error> {
+    // The topic can't be in the path of `/`.
+    if topic.is_empty() {
+        return Err(Error::Topic.because("missing topic"));
     }
+    config
+        .acl
+        .authorize(
+            &identity,
+            &connect_info.broker,
+            [topic.as_str()],
+            Access::Publish,
+        )
+        .await?;
+    Broker::new(
+        connect_info,
+        MessageGroup::Flat(Message::clear_retained(topic)),
+    )
+    .publish(&pool, &config, &PublishOptions::new(&config))
+    .await
+    .into_result()?;
 
     Ok(StatusCode::OK)
 }
 
+// The query publish route can be triggered by any link, so it is guarded by a token, checked
+// before anything is extracted from the request.
+async fn check_query_token<B>(
+    State(config): State<Arc<Config>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response, Error> {
+    let token = query_param(req.uri(), "token").ok_or(Error::Unauthorized)?;
+    if !matches!(&config.query_publish_token, Some(expected) if constant_time_eq(&token, expected))
+    {
+        return Err(Error::Unauthorized);
+    }
+    Ok(next.run(req).await)
+}
+
+// Publishes a message described by the query string.
+async fn query_publish_handler(
+    State(config): State<Arc<Config>>,
+    State(pool): State<Arc<Pool>>,
+    identity: Identity,
+    options: PublishOptions,
+    QueryConnectInfo(connect_info): QueryConnectInfo,
+    Topic(topic): Topic,
+    uri: Uri,
+) -> Result<PublishReport, Error> {
+    config
+        .acl
+        .authorize(
+            &identity,
+            &connect_info.broker,
+            [topic.as_str()],
+            Access::Publish,
+        )
+        .await?;
+    let message = Message::from_query(topic, &uri)?;
+    if options.strict {
+        message.payload().map_err(|err| err.field("payload"))?;
+    }
+    Ok(
+        PublishRequest::Single(Broker::new(connect_info, MessageGroup::Flat(message)))
+            .publish(&pool, &config, &options)
+            .await,
+    )
+}
+
 async fn subscribe_handler(
-    connect_info: ConnectInfo,
+    State(state): State<AppState>,
+    identity: Identity,
+    websocket: Option<WebSocketUpgrade>,
+    connect_info: Result<ConnectInfo, Error>,
     Topic(topic): Topic,
+    options: SubscribeOptions,
     headers: HeaderMap,
 ) -> Result<Response, Error> {
-    let mut client = AsyncClient::new(
-        CreateOptionsBuilder::new()
-            .server_uri(connect_info.broker)
-            .finalize(),
-    )
-    .map_err(|_| Error::ClientInformation)?;
-
-    let opts = match connect_info.credentials {
-        Some(Credentials { username, password }) => ConnectOptionsBuilder::new()
-            .user_name(username)
-            .password(password)
-            .finalize(),
-        None => ConnectOptions::new(),
-    };
+    if let Some(websocket) = websocket {
+        if !state.config.enable_websocket {
+            return Err(Error::WebSocketDisabled);
+        }
+        return Ok(websocket::upgrade(
+            websocket,
+            connect_info.ok(),
+            identity,
+            state,
+        ));
+    }
+    let connect_info = connect_info?;
+    state
+        .config
+        .acl
+        .authorize(
+            &identity,
+            &connect_info.broker,
+            [topic.as_str()],
+            Access::Subscribe,
+        )
+        .await?;
 
-    let mut stream = client.get_stream(1);
-    client
-        .connect(opts)
-        .await
-        .map_err(|_| Error::BrokerConnection)?;
-    client
-        .subscribe(topic, QOS_2)
-        .await
-        .map_err(|_| Error::Subscription)?;
+    let streaming = header_str(&headers, header::ACCEPT) == Some("text/event-stream");
+    let mut subscriber = state
+        .hub
+        .subscribe(
+            connect_info,
+            topic,
+            options.qos,
+            header_str(&headers, "Last-Event-ID").filter(|_| streaming),
+            &state.config,
+        )
+        .await?;
+    if streaming {
+        return Ok(event_stream(subscriber, options.layout).into_response());
+    }
+
+    if options.count > 1 {
+        let messages = collect(
+            &mut subscriber,
+            options.count,
+            options.timeout,
+            options.layout.as_ref(),
+        )
+        .await;
+        if messages.is_empty() {
+            return Err(Error::PublishTimeout);
+        }
+        return Ok(Json(messages).into_response());
+    }
 
-    let message = timeout(Duration::from_secs(5 * 60), stream.next())
+    let message = timeout(options.timeout, subscriber.recv())
         .await
         .map_err(|_| Error::PublishTimeout)?
-        .flatten()
         .ok_or(Error::MessageReception)?;
 
-    client
-        .disconnect(None)
-        .await
-        .map_err(|_| Error::Disconnect)?;
-
+    let properties = MessageProperties::from_mqtt(message.properties()).to_headers();
+    let decoded = options
+        .layout
+        .as_ref()
+        .and_then(|layout| layout.unpack(message.payload()));
     Ok(
         if header_str(&headers, header::ACCEPT) == Some("text/plain") {
             (
+                properties,
                 [
                     (header::CONTENT_TYPE, "text/plain"),
                     (HeaderName::from_static("x-topic"), message.topic()),
@@ -129,8 +300,16 @@ async fn subscribe_handler(
                 message.payload_str().into_owned(),
             )
                 .into_response()
+        } else if let Some(values) = decoded {
+            (
+                properties,
+                [(HeaderName::from_static("x-topic"), message.topic())],
+                Json(Value::Object(values)),
+            )
+                .into_response()
         } else {
             (
+                properties,
                 [(HeaderName::from_static("x-topic"), message.topic())],
                 message.payload().to_vec(),
             )
@@ -1,10 +1,39 @@
-use axum::http::{header::AsHeaderName, HeaderMap};
-use url::{ParseError as UrlParseError, Url};
+use axum::http::{header::AsHeaderName, HeaderMap, Uri};
+use url::{form_urlencoded, ParseError as UrlParseError, Url};
+
+const SECURE_SCHEMES: [&str; 3] = ["ssl", "mqtts", "wss"];
 
 pub fn header_str<H: AsHeaderName>(headers: &HeaderMap, name: H) -> Option<&str> {
     headers.get(name)?.to_str().ok()
 }
 
+pub fn query_param(uri: &Uri, name: &str) -> Option<String> {
+    form_urlencoded::parse(uri.query()?.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+pub fn parse_bool(input: &str) -> Option<bool> {
+    match input {
+        "1" | "true" => Some(true),
+        "0" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+// Compares secrets without leaking the position of the first difference through timing.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+pub fn parse_qos(input: &str) -> Option<i32> {
+    input.parse().ok().filter(|qos| (0..=2).contains(qos))
+}
+
 pub fn parse_url_with_default(input: &str) -> Result<Url, UrlParseError> {
     match input.parse() {
         Ok(url) => Ok(url),
@@ -12,3 +41,7 @@ pub fn parse_url_with_default(input: &str) -> Result<Url, UrlParseError> {
         Err(err) => Err(err),
     }
 }
+
+pub fn is_secure_url(url: &Url) -> bool {
+    SECURE_SCHEMES.contains(&url.scheme())
+}
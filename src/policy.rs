@@ -0,0 +1,360 @@
+use std::{error::Error as StdError, future::Future, io, net::IpAddr, str::FromStr};
+
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer};
+use tokio::net::lookup_host;
+use url::{Host, Url};
+
+use crate::Error;
+
+// Restricts the brokers httq connects to, so it can't be used to reach arbitrary hosts.
+#[derive(Default, Debug)]
+pub struct BrokerPolicy {
+    // Any broker is allowed when empty.
+    allow: Vec<Rule>,
+    deny: Vec<Rule>,
+}
+
+impl BrokerPolicy {
+    pub fn new(allow: &str, deny: &str) -> Result<Self, Box<dyn StdError + Send + Sync>> {
+        let parse = |rules: &str| {
+            rules
+                .split(',')
+                .map(str::trim)
+                .filter(|rule| !rule.is_empty())
+                .map(str::parse)
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(Self {
+            allow: parse(allow)?,
+            deny: parse(deny)?,
+        })
+    }
+
+    // Reads an allow list, as a list of rules.
+    pub(crate) fn deserialize_allowed<'de, D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Self {
+            allow: Vec::<String>::deserialize(deserializer)?
+                .iter()
+                .map(|rule| rule.parse())
+                .collect::<Result<_, _>>()
+                .map_err(serde::de::Error::custom)?,
+            deny: Vec::new(),
+        })
+    }
+
+    // Host names are resolved to match network rules, returning the checked address to connect to
+    // so a DNS server changing its answers can't redirect the connection. Host names that can't
+    // be resolved are rejected, as their addresses can't be checked.
+    pub async fn check(&self, url: &Url) -> Result<Option<IpAddr>, Error> {
+        self.check_with(url, |host, port| async move {
+            Ok(lookup_host((host.as_str(), port))
+                .await?
+                .map(|address| address.ip())
+                .collect())
+        })
+        .await
+    }
+
+    // Checks a broker, resolving its host name using `resolve`.
+    async fn check_with<F, R>(&self, url: &Url, resolve: F) -> Result<Option<IpAddr>, Error>
+    where
+        F: FnOnce(String, u16) -> R,
+        R: Future<Output = io::Result<Vec<IpAddr>>>,
+    {
+        if self.allow.is_empty() && self.deny.is_empty() {
+            return Ok(None);
+        }
+        let mut broker = Broker::new(url).ok_or(Error::BrokerUrl)?;
+        if let Host::Domain(domain) = &broker.host {
+            if self.has_network_rules() {
+                broker.addresses = resolve(domain.clone(), broker.port)
+                    .await
+                    .map_err(|err| Error::BrokerForbidden.because(err))?
+                    .into_iter()
+                    .map(|address| address.to_canonical())
+                    .collect();
+                if broker.addresses.is_empty() {
+                    return Err(Error::BrokerForbidden.because("host name resolution failed"));
+                }
+            }
+        }
+        self.check_broker(&broker)?;
+        Ok(match broker.host {
+            Host::Domain(_) => broker.addresses.first().copied(),
+            _ => None,
+        })
+    }
+
+    fn check_broker(&self, broker: &Broker) -> Result<(), Error> {
+        let denied = self.deny.iter().any(|rule| rule.matches(broker, false));
+        let allowed =
+            self.allow.is_empty() || self.allow.iter().any(|rule| rule.matches(broker, true));
+        if denied || !allowed {
+            return Err(Error::BrokerForbidden);
+        }
+        Ok(())
+    }
+
+    fn has_network_rules(&self) -> bool {
+        self.allow
+            .iter()
+            .chain(&self.deny)
+            .any(|rule| matches!(rule.host, HostPattern::Network(_)))
+    }
+}
+
+struct Broker {
+    scheme: String,
+    host: Host,
+    port: u16,
+    // Addresses a host name resolves to, IPv4-mapped IPv6 addresses being converted to IPv4 so
+    // they match IPv4 networks.
+    addresses: Vec<IpAddr>,
+}
+
+impl Broker {
+    fn new(url: &Url) -> Option<Self> {
+        // IPv4 addresses are only parsed as such for special schemes (`ws`, `wss`).
+        let host = match url.host()?.to_owned() {
+            Host::Domain(domain) => match domain.parse() {
+                Ok(IpAddr::V4(ip)) => Host::Ipv4(ip),
+                Ok(IpAddr::V6(ip)) => Host::Ipv6(ip),
+                Err(_) => Host::Domain(domain),
+            },
+            host => host,
+        };
+        let addresses = match host {
+            Host::Ipv4(ip) => vec![IpAddr::V4(ip)],
+            Host::Ipv6(ip) => vec![IpAddr::V6(ip).to_canonical()],
+            Host::Domain(_) => Vec::new(),
+        };
+        Some(Self {
+            scheme: url.scheme().to_owned(),
+            port: url.port().or_else(|| default_port(url.scheme()))?,
+            host,
+            addresses,
+        })
+    }
+}
+
+// Ports used by the C library when the URL doesn't specify any.
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "tcp" | "mqtt" => Some(1883),
+        "ssl" | "mqtts" => Some(8883),
+        "ws" => Some(80),
+        "wss" => Some(443),
+        _ => None,
+    }
+}
+
+// `[scheme://]host[:port]`, where host is `*`, a host name optionally starting with `*.`, an IP
+// address or a CIDR block (bracketed when IPv6 and followed by a port), and port is a number or `*`.
+#[derive(PartialEq, Debug)]
+struct Rule {
+    scheme: Option<String>,
+    host: HostPattern,
+    port: Option<u16>,
+}
+
+#[derive(PartialEq, Debug)]
+enum HostPattern {
+    Any,
+    Domain(String),
+    // Including the leading dot.
+    Subdomains(String),
+    Network(IpNet),
+}
+
+impl Rule {
+    // A host name only matches a network rule if all (for allow rules) or any (for deny rules) of
+    // its addresses are part of the network.
+    fn matches(&self, broker: &Broker, all_addresses: bool) -> bool {
+        if matches!(&self.scheme, Some(scheme) if *scheme != broker.scheme) {
+            return false;
+        }
+        if matches!(self.port, Some(port) if port != broker.port) {
+            return false;
+        }
+        match (&self.host, &broker.host) {
+            (HostPattern::Any, _) => true,
+            (HostPattern::Domain(expected), Host::Domain(domain)) => {
+                domain.eq_ignore_ascii_case(expected)
+            }
+            (HostPattern::Subdomains(suffix), Host::Domain(domain)) => {
+                domain.to_ascii_lowercase().ends_with(suffix.as_str())
+            }
+            (HostPattern::Network(network), _) => {
+                let mut addresses = broker.addresses.iter();
+                if all_addresses {
+                    !broker.addresses.is_empty() && addresses.all(|ip| network.contains(ip))
+                } else {
+                    addresses.any(|ip| network.contains(ip))
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid broker rule {}", input);
+        let (scheme, rest) = match input.split_once("://") {
+            Some((scheme, rest)) => (Some(scheme.to_ascii_lowercase()), rest),
+            None => (None, input),
+        };
+        let (host, port) = if let Some(bracketed) = rest.strip_prefix('[') {
+            let (host, rest) = bracketed.split_once(']').ok_or_else(invalid)?;
+            match rest {
+                "" => (host, None),
+                _ => (host, Some(rest.strip_prefix(':').ok_or_else(invalid)?)),
+            }
+        } else {
+            match rest.split_once(':') {
+                // Unbracketed IPv6 addresses can't be followed by a port.
+                Some((_, port)) if port.contains(':') => (rest, None),
+                Some((host, port)) => (host, Some(port)),
+                None => (rest, None),
+            }
+        };
+
+        Ok(Self {
+            scheme,
+            host: match host {
+                "*" => HostPattern::Any,
+                _ if host.contains('/') => {
+                    HostPattern::Network(host.parse().map_err(|_| invalid())?)
+                }
+                _ => match host.parse::<IpAddr>() {
+                    Ok(ip) => HostPattern::Network(IpNet::from(ip)),
+                    Err(_) => match host.strip_prefix('*') {
+                        Some(suffix) if suffix.starts_with('.') => {
+                            HostPattern::Subdomains(suffix.to_ascii_lowercase())
+                        }
+                        Some(_) => return Err(invalid()),
+                        None if host.is_empty() => return Err(invalid()),
+                        None => HostPattern::Domain(host.to_ascii_lowercase()),
+                    },
+                },
+            },
+            port: match port {
+                None | Some("*") => None,
+                Some(port) => Some(port.parse().map_err(|_| invalid())?),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, ErrorKind},
+        net::{IpAddr, Ipv4Addr},
+    };
+
+    use super::{BrokerPolicy, HostPattern, Rule};
+    use crate::Error;
+
+    // Resolves host names using a fixed table, so that tests don't depend on DNS.
+    async fn check(policy: &BrokerPolicy, url: &str) -> Result<Option<IpAddr>, Error> {
+        let resolve = |host: String, _port| async move {
+            match host.as_str() {
+                "localhost" => Ok(vec![Ipv4Addr::LOCALHOST.into()]),
+                "broker.com" => Ok(vec![Ipv4Addr::new(203, 0, 113, 1).into()]),
+                "mapped.broker.com" => Ok(vec!["::ffff:10.1.2.3".parse().unwrap()]),
+                _ => Err(io::Error::from(ErrorKind::NotFound)),
+            }
+        };
+        policy.check_with(&url.parse().unwrap(), resolve).await
+    }
+
+    #[test]
+    fn rules() {
+        assert_eq!(
+            "mqtts://*.example.com:8883".parse::<Rule>().unwrap(),
+            Rule {
+                scheme: Some("mqtts".to_owned()),
+                host: HostPattern::Subdomains(".example.com".to_owned()),
+                port: Some(8883),
+            }
+        );
+        assert_eq!(
+            "[fd00::/8]:*".parse::<Rule>().unwrap(),
+            Rule {
+                scheme: None,
+                host: HostPattern::Network("fd00::/8".parse().unwrap()),
+                port: None,
+            }
+        );
+        assert_eq!(
+            "::1".parse::<Rule>().unwrap().host,
+            HostPattern::Network("::1/128".parse().unwrap())
+        );
+        assert!("broker.com:port".parse::<Rule>().is_err());
+        assert!("10.0.0.0/33".parse::<Rule>().is_err());
+        assert!("*broker.com".parse::<Rule>().is_err());
+    }
+
+    #[tokio::test]
+    async fn allow_all() {
+        assert!(check(&BrokerPolicy::default(), "tcp://10.0.0.1")
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn allow_list() {
+        let policy = BrokerPolicy::new("mqtts://*.example.com, tcp://broker.com:1883", "").unwrap();
+        assert!(check(&policy, "mqtts://eu.example.com").await.is_ok());
+        assert!(check(&policy, "tcp://Broker.com").await.is_ok());
+        assert!(check(&policy, "tcp://eu.example.com").await.is_err());
+        assert!(check(&policy, "tcp://broker.com:1884").await.is_err());
+        assert!(matches!(
+            check(&policy, "tcp://10.0.0.1").await,
+            Err(Error::BrokerForbidden)
+        ));
+    }
+
+    #[tokio::test]
+    async fn deny_list() {
+        let policy = BrokerPolicy::new("", "127.0.0.0/8, [::1], 10.0.0.0/8:*, *:22").unwrap();
+        assert!(check(&policy, "tcp://127.0.0.1").await.is_err());
+        assert!(check(&policy, "tcp://[::1]:1883").await.is_err());
+        assert!(check(&policy, "ws://10.1.2.3").await.is_err());
+        assert!(check(&policy, "tcp://broker.com:22").await.is_err());
+        assert!(check(&policy, "tcp://192.168.1.1").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn ipv4_mapped() {
+        let policy = BrokerPolicy::new("", "127.0.0.0/8").unwrap();
+        assert!(check(&policy, "tcp://[::ffff:127.0.0.1]").await.is_err());
+        assert!(check(&policy, "tcp://[::ffff:7f00:1]:1883").await.is_err());
+        assert!(check(&policy, "tcp://[::ffff:192.168.1.1]").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn resolution() {
+        let policy = BrokerPolicy::new("", "10.0.0.0/8").unwrap();
+        assert!(matches!(
+            check(&policy, "tcp://broker.invalid")
+                .await
+                .as_ref()
+                .map_err(Error::kind),
+            Err(Error::BrokerForbidden)
+        ));
+        let address = check(&policy, "tcp://localhost").await.unwrap();
+        assert!(address.unwrap().is_loopback());
+        assert!(check(&policy, "tcp://mapped.broker.com").await.is_err());
+        // Host names are only resolved for network rules.
+        let policy = BrokerPolicy::new("", "*.internal").unwrap();
+        assert_eq!(check(&policy, "tcp://broker.invalid").await.unwrap(), None);
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    collections::HashMap,
+    ops::Deref,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use paho_mqtt::AsyncClient;
+use tokio::{sync::Mutex as AsyncMutex, time::interval};
+
+use crate::{client, config::Config, connect_info::ConnectInfo, Error};
+
+struct Slot {
+    client: AsyncMutex<Option<AsyncClient>>,
+    last_used: Mutex<Instant>,
+}
+
+pub struct Pool {
+    slots: Mutex<HashMap<ConnectInfo, Arc<Slot>>>,
+}
+
+impl Pool {
+    pub fn new(idle_timeout: Duration) -> Arc<Self> {
+        let pool = Arc::new(Self {
+            slots: Mutex::new(HashMap::new()),
+        });
+        tokio::spawn(Self::evict_idle(Arc::downgrade(&pool), idle_timeout));
+        pool
+    }
+
+    // Returns a connected client, reusing a pooled connection when one is still alive.
+    pub async fn get(&self, connect_info: ConnectInfo, config: &Config) -> Result<Lease, Error> {
+        let slot = Arc::clone(
+            self.slots
+                .lock()
+                .unwrap()
+                .entry(connect_info.clone())
+                .or_insert_with(|| {
+                    Arc::new(Slot {
+                        client: AsyncMutex::new(None),
+                        last_used: Mutex::new(Instant::now()),
+                    })
+                }),
+        );
+
+        let client = {
+            let mut client = slot.client.lock().await;
+            match &*client {
+                Some(existing) if existing.is_connected() => existing.clone(),
+                _ => {
+                    let fresh = client::create(&connect_info, config)?;
+                    client::connect(&fresh, connect_info, config).await?;
+                    client.insert(fresh).clone()
+                }
+            }
+        };
+        Ok(Lease { client, slot })
+    }
+
+    async fn evict_idle(pool: Weak<Self>, idle_timeout: Duration) {
+        let mut ticker = interval((idle_timeout / 2).max(Duration::from_secs(1)));
+        loop {
+            ticker.tick().await;
+            let pool = match pool.upgrade() {
+                Some(pool) => pool,
+                None => return,
+            };
+
+            let mut evicted = Vec::new();
+            pool.slots.lock().unwrap().retain(|_, slot| {
+                // A slot referenced outside of the map is currently leased.
+                let idle = Arc::strong_count(slot) == 1
+                    && slot.last_used.lock().unwrap().elapsed() >= idle_timeout;
+                if idle {
+                    evicted.push(Arc::clone(slot));
+                }
+                !idle
+            });
+            for slot in evicted {
+                if let Some(client) = slot.client.lock().await.take() {
+                    let _ = client.disconnect(None).await;
+                }
+            }
+        }
+    }
+}
+
+pub struct Lease {
+    client: AsyncClient,
+    slot: Arc<Slot>,
+}
+
+impl Deref for Lease {
+    type Target = AsyncClient;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        *self.slot.last_used.lock().unwrap() = Instant::now();
+    }
+}
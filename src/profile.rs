@@ -0,0 +1,190 @@
+use std::{collections::HashMap, error::Error as StdError, fmt, fs, path::Path, str::FromStr};
+
+use serde::{de::Unexpected, Deserialize, Deserializer};
+use url::{ParseError as UrlParseError, Url};
+
+use crate::{
+    config::Config,
+    connect_info::{ClientOptions, ConnectInfo, Credentials, TlsOptions},
+    misc::parse_url_with_default,
+    Error,
+};
+
+// A broker given by its URL, or by the name of a server-side profile prefixed with '@'.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Target {
+    Url(Url),
+    Profile(String),
+}
+
+impl Target {
+    pub fn url<'a>(&'a self, config: &'a Config) -> Result<&'a Url, Error> {
+        match self {
+            Self::Url(url) => Ok(url),
+            Self::Profile(name) => Ok(&config.profiles.get(name).ok_or(Error::Profile)?.url),
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = UrlParseError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.strip_prefix('@') {
+            Some(name) => Ok(Self::Profile(name.to_owned())),
+            None => parse_url_with_default(input).map(Self::Url),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Url(url) => write!(f, "{}", url),
+            Self::Profile(name) => write!(f, "@{}", name),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct Profiles {
+    // Used by header requests that don't specify any broker.
+    pub default: Option<String>,
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+}
+
+impl Profiles {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn StdError + Send + Sync>> {
+        let profiles: Self = toml::from_str(&fs::read_to_string(path)?)?;
+        if let Some(default) = &profiles.default {
+            if !profiles.profiles.contains_key(default) {
+                return Err(format!("default broker profile {} not found", default).into());
+            }
+        }
+        Ok(profiles)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        self.profiles.get(name)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Profile {
+    #[serde(
+        alias = "broker",
+        alias = "host",
+        alias = "hostname",
+        deserialize_with = "Profile::deserialize_url"
+    )]
+    url: Url,
+    #[serde(flatten)]
+    credentials: Option<Credentials>,
+    #[serde(flatten)]
+    tls: TlsOptions,
+    #[serde(flatten)]
+    options: ClientOptions,
+}
+
+impl Profile {
+    // Options specified by the request take precedence over the profile ones, but the broker
+    // itself and its TLS options can't be changed.
+    pub fn connect_info(
+        &self,
+        credentials: Option<Credentials>,
+        options: ClientOptions,
+    ) -> ConnectInfo {
+        ConnectInfo {
+            broker: self.url.clone(),
+            credentials: credentials.or_else(|| self.credentials.clone()),
+            tls: self.tls.clone(),
+            options: ClientOptions {
+                mqtt_version: options.mqtt_version.or(self.options.mqtt_version),
+                client_id: options.client_id.or_else(|| self.options.client_id.clone()),
+                keep_alive: options.keep_alive.or(self.options.keep_alive),
+            },
+        }
+    }
+
+    fn deserialize_url<'de, D>(deserializer: D) -> Result<Url, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let input = String::deserialize(deserializer)?;
+        parse_url_with_default(&input).map_err(|err| {
+            serde::de::Error::invalid_value(Unexpected::Str(&input), &err.to_string().as_str())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Profiles, Target};
+    use crate::connect_info::Credentials;
+
+    fn profiles() -> Profiles {
+        toml::from_str(
+            r#"
+            default = "prod"
+
+            [profiles.prod]
+            url = "mqtts://broker.com"
+            username = "user1"
+            password = "qwerty"
+            mqttVersion = 5
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn target() {
+        assert_eq!(
+            "@prod".parse::<Target>().unwrap(),
+            Target::Profile("prod".to_owned())
+        );
+        assert_eq!(
+            "broker.com".parse::<Target>().unwrap(),
+            Target::Url("tcp://broker.com".parse().unwrap())
+        );
+        assert_eq!(
+            Target::Profile("prod".to_owned()).to_string(),
+            "@prod".to_owned()
+        );
+    }
+
+    #[test]
+    fn connect_info() {
+        let profiles = profiles();
+        assert_eq!(profiles.default.as_deref(), Some("prod"));
+        assert!(profiles.get("staging").is_none());
+
+        let connect_info = profiles
+            .get("prod")
+            .unwrap()
+            .connect_info(None, Default::default());
+        assert_eq!(connect_info.broker.as_str(), "mqtts://broker.com");
+        assert_eq!(
+            connect_info.credentials,
+            Some(Credentials {
+                username: "user1".to_owned(),
+                password: "qwerty".to_owned(),
+            })
+        );
+        assert_eq!(connect_info.options.mqtt_version, Some(5));
+    }
+
+    #[test]
+    fn request_overrides() {
+        let credentials = Credentials {
+            username: "user2".to_owned(),
+            password: "azerty".to_owned(),
+        };
+        let connect_info = profiles()
+            .get("prod")
+            .unwrap()
+            .connect_info(Some(credentials.clone()), Default::default());
+        assert_eq!(connect_info.credentials, Some(credentials));
+    }
+}
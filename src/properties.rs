@@ -0,0 +1,218 @@
+use std::collections::BTreeMap;
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use base64::engine::{general_purpose::STANDARD as BASE64, Engine as _};
+use paho_mqtt::{Properties, PropertyCode};
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+// MQTT v5 message properties, which can't be published to brokers speaking an older protocol
+// version.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageProperties {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_expiry_interval: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correlation_data: Option<String>,
+    // Binary, so base64 encoded when not UTF-8, like received payloads.
+    #[serde(default, skip_serializing_if = "DataType::is_string")]
+    pub correlation_data_type: DataType,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub user_properties: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_format_indicator: Option<u8>,
+}
+
+impl MessageProperties {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn to_mqtt(&self) -> Result<Properties, Error> {
+        let mut properties = Properties::new();
+        let push =
+            |result: paho_mqtt::Result<()>| result.map_err(|err| Error::Properties.because(err));
+        if let Some(content_type) = &self.content_type {
+            push(properties.push_string(PropertyCode::ContentType, content_type))?;
+        }
+        if let Some(interval) = self.message_expiry_interval {
+            push(properties.push_u32(PropertyCode::MessageExpiryInterval, interval))?;
+        }
+        if let Some(topic) = &self.response_topic {
+            push(properties.push_string(PropertyCode::ResponseTopic, topic))?;
+        }
+        if let Some(data) = &self.correlation_data {
+            let data = match self.correlation_data_type {
+                DataType::String => data.as_bytes().to_vec(),
+                DataType::Base64 => BASE64
+                    .decode(data)
+                    .map_err(|err| Error::Properties.because(err).field("correlationData"))?,
+            };
+            push(properties.push_binary(PropertyCode::CorrelationData, data))?;
+        }
+        for (key, value) in &self.user_properties {
+            push(properties.push_string_pair(PropertyCode::UserProperty, key, value))?;
+        }
+        if let Some(indicator) = self.payload_format_indicator {
+            push(properties.push_byte(PropertyCode::PayloadFormatIndicator, indicator))?;
+        }
+        Ok(properties)
+    }
+
+    pub fn from_mqtt(properties: &Properties) -> Self {
+        let (correlation_data, correlation_data_type) =
+            match properties.get_binary(PropertyCode::CorrelationData) {
+                Some(data) => match String::from_utf8(data) {
+                    Ok(data) => (Some(data), DataType::String),
+                    Err(err) => (Some(BASE64.encode(err.into_bytes())), DataType::Base64),
+                },
+                None => (None, DataType::String),
+            };
+        Self {
+            content_type: properties.get_string(PropertyCode::ContentType),
+            message_expiry_interval: properties.get_val(PropertyCode::MessageExpiryInterval),
+            response_topic: properties.get_string(PropertyCode::ResponseTopic),
+            correlation_data,
+            correlation_data_type,
+            user_properties: properties.user_iter().collect(),
+            payload_format_indicator: properties.get_val(PropertyCode::PayloadFormatIndicator),
+        }
+    }
+
+    // Properties that can't be represented as header values are left out.
+    pub fn to_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let mut insert = |name: &'static str, value: String| {
+            if let Ok(value) = HeaderValue::try_from(value) {
+                headers.append(HeaderName::from_static(name), value);
+            }
+        };
+        if let Some(content_type) = &self.content_type {
+            insert("content-type", content_type.clone());
+        }
+        if let Some(interval) = self.message_expiry_interval {
+            insert("x-message-expiry-interval", interval.to_string());
+        }
+        if let Some(topic) = &self.response_topic {
+            insert("x-response-topic", topic.clone());
+        }
+        if let Some(data) = &self.correlation_data {
+            insert("x-correlation-data", data.clone());
+            if self.correlation_data_type == DataType::Base64 {
+                insert("x-correlation-data-type", "base64".to_owned());
+            }
+        }
+        for (key, value) in &self.user_properties {
+            insert("x-user-property", format!("{key}={value}"));
+        }
+        if let Some(indicator) = self.payload_format_indicator {
+            insert("x-payload-format-indicator", indicator.to_string());
+        }
+        headers
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum DataType {
+    #[default]
+    String,
+    Base64,
+}
+
+impl DataType {
+    fn is_string(&self) -> bool {
+        *self == Self::String
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use paho_mqtt::{Properties, PropertyCode};
+    use serde_json::json;
+
+    use super::{DataType, MessageProperties};
+    use crate::Error;
+
+    fn properties() -> MessageProperties {
+        serde_json::from_value(json!({
+            "contentType": "application/json",
+            "messageExpiryInterval": 60,
+            "responseTopic": "replies/door",
+            "correlationData": "42",
+            "userProperties": {
+                "device": "door-1",
+                "route": "north",
+            },
+            "payloadFormatIndicator": 1,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn empty() {
+        assert!(MessageProperties::default().is_empty());
+        assert!(!properties().is_empty());
+    }
+
+    #[test]
+    fn mqtt_round_trip() {
+        let properties = properties();
+        assert_eq!(
+            MessageProperties::from_mqtt(&properties.to_mqtt().unwrap()),
+            properties
+        );
+    }
+
+    #[test]
+    fn binary_correlation_data() {
+        let mut mqtt = Properties::new();
+        mqtt.push_binary(PropertyCode::CorrelationData, [0, 159, 146, 150])
+            .unwrap();
+        let properties = MessageProperties::from_mqtt(&mqtt);
+        assert_eq!(properties.correlation_data.as_deref(), Some("AJ+Slg=="));
+        assert_eq!(properties.correlation_data_type, DataType::Base64);
+        assert_eq!(
+            serde_json::to_value(&properties).unwrap(),
+            json!({"correlationData": "AJ+Slg==", "correlationDataType": "base64"})
+        );
+        assert_eq!(properties.to_headers()["x-correlation-data-type"], "base64");
+        assert_eq!(
+            MessageProperties::from_mqtt(&properties.to_mqtt().unwrap()),
+            properties
+        );
+
+        let properties: MessageProperties = serde_json::from_value(json!({
+            "correlationData": "not base64",
+            "correlationDataType": "base64",
+        }))
+        .unwrap();
+        assert!(matches!(
+            properties.to_mqtt().as_ref().map_err(Error::kind),
+            Err(Error::Properties)
+        ));
+    }
+
+    #[test]
+    fn headers() {
+        let headers = properties().to_headers();
+        assert_eq!(headers["content-type"], "application/json");
+        assert_eq!(headers["x-message-expiry-interval"], "60");
+        assert_eq!(headers["x-response-topic"], "replies/door");
+        assert_eq!(headers["x-correlation-data"], "42");
+        assert_eq!(headers["x-payload-format-indicator"], "1");
+        assert_eq!(
+            headers
+                .get_all("x-user-property")
+                .iter()
+                .collect::<Vec<_>>(),
+            ["device=door-1", "route=north"]
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use std::time::Duration;
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Serialize, Serializer};
+
+use crate::Error;
+
+#[derive(Serialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PublishReport {
+    pub brokers: Vec<BrokerReport>,
+}
+
+impl PublishReport {
+    // Every message delivered: 200, nothing delivered: the status of the first error, 207 otherwise.
+    fn status_code(&self) -> StatusCode {
+        let mut messages = self.brokers.iter().flat_map(|broker| &broker.messages);
+        if self.brokers.iter().all(BrokerReport::is_delivered) {
+            StatusCode::OK
+        } else if messages.any(|message| message.status == Status::Delivered) {
+            StatusCode::MULTI_STATUS
+        } else {
+            self.brokers
+                .iter()
+                .find_map(BrokerReport::first_error)
+                .map(Error::status_code)
+                .unwrap_or(StatusCode::BAD_GATEWAY)
+        }
+    }
+}
+
+impl IntoResponse for PublishReport {
+    fn into_response(self) -> Response {
+        (self.status_code(), Json(self)).into_response()
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BrokerReport {
+    // The broker URL, or the name of its profile.
+    pub broker: String,
+    // Set when the broker couldn't be reached, in which case no message was sent.
+    #[serde(
+        serialize_with = "serialize_error",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub error: Option<Error>,
+    #[serde(rename = "durationMs", serialize_with = "serialize_duration")]
+    pub duration: Duration,
+    pub messages: Vec<MessageReport>,
+}
+
+impl BrokerReport {
+    pub fn new(broker: String, topics: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            broker,
+            error: None,
+            duration: Duration::ZERO,
+            messages: topics
+                .into_iter()
+                .enumerate()
+                .map(|(index, topic)| MessageReport {
+                    index,
+                    topic,
+                    status: Status::Skipped,
+                    error: None,
+                    duration: None,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn is_delivered(&self) -> bool {
+        self.error.is_none()
+            && self
+                .messages
+                .iter()
+                .all(|message| message.status == Status::Delivered)
+    }
+
+    fn first_error(&self) -> Option<&Error> {
+        self.error.as_ref().or_else(|| {
+            self.messages
+                .iter()
+                .find_map(|message| message.error.as_ref())
+        })
+    }
+
+    // Returns the first error, for callers only interested in the overall outcome.
+    pub fn into_result(self) -> Result<(), Error> {
+        let delivered = self.is_delivered();
+        let broker = self.broker;
+        match self.error {
+            Some(err) => Err(err.broker(broker)),
+            None => match self
+                .messages
+                .into_iter()
+                .find_map(|message| Some(message.error?.message(message.index)))
+            {
+                Some(err) => Err(err.broker(broker)),
+                None if delivered => Ok(()),
+                None => Err(Error::Publish.broker(broker)),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageReport {
+    // MQTT message ids are kept private by the client library's delivery tokens, so messages are
+    // identified by their position in the request (as documented in the README).
+    pub index: usize,
+    pub topic: String,
+    pub status: Status,
+    #[serde(
+        serialize_with = "serialize_error",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub error: Option<Error>,
+    #[serde(
+        rename = "durationMs",
+        serialize_with = "serialize_optional_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub duration: Option<Duration>,
+}
+
+impl MessageReport {
+    pub fn complete(&mut self, result: Result<(), Error>, duration: Duration) {
+        self.duration = Some(duration);
+        match result {
+            Ok(()) => self.status = Status::Delivered,
+            Err(err) => {
+                self.status = Status::Failed;
+                self.error = Some(err);
+            }
+        }
+    }
+}
+
+#[derive(Serialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum Status {
+    Delivered,
+    Failed,
+    // Not attempted, because of a previous failure.
+    Skipped,
+}
+
+fn serialize_error<S: Serializer>(error: &Option<Error>, serializer: S) -> Result<S::Ok, S::Error> {
+    match error {
+        Some(error) => serializer.collect_str(error),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_duration<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_millis() as u64)
+}
+
+fn serialize_optional_duration<S: Serializer>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match duration {
+        Some(duration) => serialize_duration(duration, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use super::{BrokerReport, PublishReport};
+    use crate::Error;
+
+    fn broker(results: &[Option<Result<(), Error>>]) -> BrokerReport {
+        let mut report = BrokerReport::new(
+            "tcp://broker.com".to_owned(),
+            (0..results.len()).map(|index| format!("topic/{index}")),
+        );
+        for (message, result) in report.messages.iter_mut().zip(results) {
+            if let Some(result) = result {
+                let result = result.as_ref().map_err(|_| Error::Publish).copied();
+                message.complete(result, Duration::from_millis(3));
+            }
+        }
+        report
+    }
+
+    #[test]
+    fn delivered() {
+        let report = PublishReport {
+            brokers: vec![broker(&[Some(Ok(())), Some(Ok(()))])],
+        };
+        assert_eq!(report.status_code(), StatusCode::OK);
+    }
+
+    #[test]
+    fn partial_failure() {
+        let report = PublishReport {
+            brokers: vec![broker(&[Some(Ok(())), Some(Err(Error::Publish)), None])],
+        };
+        assert_eq!(report.status_code(), StatusCode::MULTI_STATUS);
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            json!({
+                "brokers": [{
+                    "broker": "tcp://broker.com",
+                    "durationMs": 0,
+                    "messages": [
+                        {"index": 0, "topic": "topic/0", "status": "delivered", "durationMs": 3},
+                        {
+                            "index": 1,
+                            "topic": "topic/1",
+                            "status": "failed",
+                            "error": "publish failed",
+                            "durationMs": 3,
+                        },
+                        {"index": 2, "topic": "topic/2", "status": "skipped"},
+                    ],
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn unreachable_broker() {
+        let mut unreachable = broker(&[None]);
+        unreachable.error = Some(Error::BrokerConnection);
+        let report = PublishReport {
+            brokers: vec![unreachable],
+        };
+        assert_eq!(report.status_code(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[test]
+    fn into_result() {
+        assert!(broker(&[Some(Ok(()))]).into_result().is_ok());
+        let failed = broker(&[Some(Ok(())), Some(Err(Error::Publish))]).into_result();
+        assert!(matches!(
+            failed.as_ref().map_err(Error::kind),
+            Err(Error::Publish)
+        ));
+        assert!(broker(&[Some(Ok(())), None]).into_result().is_err());
+    }
+}
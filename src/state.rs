@@ -0,0 +1,30 @@
+use std::sync::Arc;
+
+use axum::extract::FromRef;
+
+use crate::{config::Config, hub::Hub, pool::Pool};
+
+#[derive(Clone)]
+pub struct AppState {
+    pub config: Arc<Config>,
+    pub pool: Arc<Pool>,
+    pub hub: Arc<Hub>,
+}
+
+impl FromRef<AppState> for Arc<Config> {
+    fn from_ref(state: &AppState) -> Self {
+        Arc::clone(&state.config)
+    }
+}
+
+impl FromRef<AppState> for Arc<Pool> {
+    fn from_ref(state: &AppState) -> Self {
+        Arc::clone(&state.pool)
+    }
+}
+
+impl FromRef<AppState> for Arc<Hub> {
+    fn from_ref(state: &AppState) -> Self {
+        Arc::clone(&state.hub)
+    }
+}
@@ -0,0 +1,327 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+    http::request::Parts,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use base64::engine::{general_purpose::STANDARD as BASE64, Engine as _};
+use futures_util::{stream, Stream, StreamExt};
+use paho_mqtt::{Message, QOS_2};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tokio::time::{timeout_at, Instant};
+
+use crate::{
+    config::Config,
+    hub::Subscriber,
+    layout::Layout,
+    misc::{header_str, parse_qos, query_param},
+    properties::MessageProperties,
+    Error,
+};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+const RESUME_WINDOW: Duration = Duration::from_secs(30);
+
+pub struct SubscribeOptions {
+    pub timeout: Duration,
+    pub qos: i32,
+    pub count: usize,
+    // Decodes payloads into JSON objects.
+    pub layout: Option<Layout>,
+}
+
+impl SubscribeOptions {
+    // Headers take precedence over query parameters, and values are capped by the server limits.
+    fn from_parts(parts: &Parts, config: &Config) -> Result<Self, Error> {
+        // Also returns the name of the header or query parameter, to report invalid values.
+        let option = |header: &'static str, param: &'static str| {
+            header_str(&parts.headers, header)
+                .map(|value| (value.to_owned(), header))
+                .or_else(|| query_param(&parts.uri, param).map(|value| (value, param)))
+        };
+        let invalid = |name| Error::SubscribeOptions.field(name);
+
+        let timeout = match option("X-Timeout", "timeout") {
+            Some((secs, name)) => secs
+                .parse()
+                .ok()
+                .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                .ok_or_else(|| invalid(name))?,
+            None => config.subscribe_timeout,
+        };
+        let qos = match option("X-Qos", "qos") {
+            Some((qos, name)) => parse_qos(&qos).ok_or_else(|| invalid(name))?,
+            None => QOS_2,
+        };
+        let count = match option("X-Count", "count") {
+            Some((count, name)) => count
+                .parse()
+                .ok()
+                .filter(|count| *count > 0)
+                .ok_or_else(|| invalid(name))?,
+            None => 1,
+        };
+        let layout = option("X-Payload-Layout", "layout")
+            .map(|(layout, name)| {
+                config
+                    .layouts
+                    .resolve(&layout, config.max_body_size)
+                    .map_err(|err| err.field(name))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            timeout: timeout.min(config.max_subscribe_timeout),
+            qos,
+            count: count.min(config.max_subscribe_count),
+            layout,
+        })
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for SubscribeOptions
+where
+    Arc<Config>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Self::from_parts(parts, &Arc::<Config>::from_ref(state))
+    }
+}
+
+#[derive(Serialize, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceivedMessage {
+    pub topic: String,
+    pub qos: i32,
+    pub retain: bool,
+    #[serde(flatten)]
+    pub payload: ReceivedPayload,
+    #[serde(flatten)]
+    pub properties: MessageProperties,
+}
+
+impl ReceivedMessage {
+    // Payloads not matching the layout are returned undecoded.
+    pub fn new(message: &Message, layout: Option<&Layout>) -> Self {
+        let payload = message.payload();
+        Self {
+            topic: message.topic().to_owned(),
+            qos: message.qos(),
+            retain: message.retained(),
+            payload: match layout.and_then(|layout| layout.unpack(payload)) {
+                Some(values) => ReceivedPayload::Struct(values),
+                None => match std::str::from_utf8(payload) {
+                    Ok(payload) => ReceivedPayload::String(payload.to_owned()),
+                    Err(_) => ReceivedPayload::Base64(BASE64.encode(payload)),
+                },
+            },
+            properties: MessageProperties::from_mqtt(message.properties()),
+        }
+    }
+}
+
+impl From<&Message> for ReceivedMessage {
+    fn from(message: &Message) -> Self {
+        Self::new(message, None)
+    }
+}
+
+#[derive(Serialize, PartialEq, Debug)]
+#[serde(tag = "payloadType", content = "payload", rename_all = "camelCase")]
+pub enum ReceivedPayload {
+    String(String),
+    Base64(String),
+    Struct(Map<String, Value>),
+}
+
+// Waits for up to `count` messages, returning the ones received before the timeout expires.
+pub async fn collect(
+    subscriber: &mut Subscriber,
+    count: usize,
+    timeout: Duration,
+    layout: Option<&Layout>,
+) -> Vec<ReceivedMessage> {
+    let deadline = Instant::now() + timeout;
+    let mut messages = Vec::with_capacity(count);
+    while messages.len() < count {
+        match timeout_at(deadline, subscriber.recv()).await {
+            Ok(Some(message)) => messages.push(ReceivedMessage::new(&message, layout)),
+            Ok(None) | Err(_) => break,
+        }
+    }
+    messages
+}
+
+// Messages the subscriber didn't keep up with are reported by a `lost` event giving their number,
+// sent before the next message.
+pub fn event_stream(
+    mut subscriber: Subscriber,
+    layout: Option<Layout>,
+) -> Sse<impl Stream<Item = Result<Event, serde_json::Error>>> {
+    subscriber.set_linger(RESUME_WINDOW);
+    let events = stream::unfold(
+        (subscriber, layout),
+        |(mut subscriber, layout)| async move {
+            let message = subscriber.recv().await?;
+            let mut events = Vec::with_capacity(2);
+            let lost = subscriber.take_lost();
+            if lost > 0 {
+                events.push(Ok(Event::default().event("lost").data(lost.to_string())));
+            }
+            let mut event = Event::default();
+            if let Some(id) = subscriber.last_id() {
+                event = event.id(id);
+            }
+            let message = ReceivedMessage::new(&message, layout.as_ref());
+            events.push(event.json_data(message));
+            Some((stream::iter(events), (subscriber, layout)))
+        },
+    );
+    Sse::new(events.flatten()).keep_alive(KeepAlive::new().interval(HEARTBEAT_INTERVAL))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::Request;
+    use paho_mqtt::{Message, MessageBuilder};
+    use serde_json::json;
+
+    use super::{ReceivedMessage, ReceivedPayload, SubscribeOptions};
+    use crate::config::Config;
+
+    fn options(req: Request<()>) -> Option<SubscribeOptions> {
+        SubscribeOptions::from_parts(&req.into_parts().0, &Config::default()).ok()
+    }
+
+    #[test]
+    fn default_options() {
+        let options = options(Request::get("/door").body(()).unwrap()).unwrap();
+        assert_eq!(options.timeout, Config::default().max_subscribe_timeout);
+        assert_eq!(options.qos, 2);
+        assert_eq!(options.count, 1);
+    }
+
+    #[test]
+    fn query_options() {
+        let options = options(
+            Request::get("/door?timeout=2.5&qos=0&count=50")
+                .body(())
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(options.timeout, Duration::from_millis(2500));
+        assert_eq!(options.qos, 0);
+        assert_eq!(options.count, 50);
+    }
+
+    #[test]
+    fn header_options() {
+        let options = options(
+            Request::get("/door?timeout=10&qos=0")
+                .header("X-Timeout", "2")
+                .header("X-Qos", "1")
+                .body(())
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(options.timeout, Duration::from_secs(2));
+        assert_eq!(options.qos, 1);
+    }
+
+    #[test]
+    fn capped_options() {
+        let options = options(
+            Request::get("/door?timeout=3600&count=100000")
+                .body(())
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(options.timeout, Config::default().max_subscribe_timeout);
+        assert_eq!(options.count, Config::default().max_subscribe_count);
+    }
+
+    #[test]
+    fn layout_option() {
+        let options = options(
+            Request::get("/door?layout=u8%20cmd")
+                .header("X-Payload-Layout", "u16le id")
+                .body(())
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(options.layout, Some("u16le id".parse().unwrap()));
+    }
+
+    #[test]
+    fn invalid_options() {
+        assert!(options(Request::get("/door?layout=u16%20id").body(()).unwrap()).is_none());
+        assert!(options(Request::get("/door?layout=@unknown").body(()).unwrap()).is_none());
+        assert!(options(Request::get("/door?qos=3").body(()).unwrap()).is_none());
+        assert!(options(Request::get("/door?count=0").body(()).unwrap()).is_none());
+        assert!(options(Request::get("/door?timeout=-1").body(()).unwrap()).is_none());
+    }
+
+    #[test]
+    fn string_payload() {
+        assert_eq!(
+            ReceivedMessage::from(&Message::new_retained("door", "open", 1)),
+            ReceivedMessage {
+                topic: "door".to_owned(),
+                qos: 1,
+                retain: true,
+                payload: ReceivedPayload::String("open".to_owned()),
+                properties: Default::default(),
+            }
+        );
+    }
+
+    #[test]
+    fn binary_payload() {
+        assert_eq!(
+            ReceivedMessage::from(
+                &MessageBuilder::new()
+                    .topic("door")
+                    .payload(vec![0, 159, 146, 150])
+                    .finalize()
+            ),
+            ReceivedMessage {
+                topic: "door".to_owned(),
+                qos: 0,
+                retain: false,
+                payload: ReceivedPayload::Base64("AJ+Slg==".to_owned()),
+                properties: Default::default(),
+            }
+        );
+    }
+
+    #[test]
+    fn struct_payload() {
+        let layout = "u8 cmd; u16be id".parse().unwrap();
+        let message = Message::new("door", vec![1, 2, 3], 0);
+        let received = serde_json::to_value(ReceivedMessage::new(&message, Some(&layout)));
+        assert_eq!(
+            received.unwrap(),
+            json!({
+                "topic": "door",
+                "qos": 0,
+                "retain": false,
+                "payloadType": "struct",
+                "payload": {"cmd": 1, "id": 515},
+            })
+        );
+        let message = Message::new("door", "open", 0);
+        assert_eq!(
+            ReceivedMessage::new(&message, Some(&layout)).payload,
+            ReceivedPayload::String("open".to_owned())
+        );
+    }
+}
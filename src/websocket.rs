@@ -0,0 +1,551 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+    response::Response,
+};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use tokio::{sync::mpsc, task::JoinHandle};
+use url::Url;
+
+use crate::{
+    acl::Access,
+    auth::Identity,
+    config::Config,
+    connect_info::{ClientOptions, ConnectInfo, Credentials, TlsOptions},
+    layout::Layout,
+    profile::Target,
+    publish::{validate_strict, Broker, Message, MessageGroup, PublishOptions, PublishRequest},
+    state::AppState,
+    subscribe::ReceivedMessage,
+    Error,
+};
+
+const OUTGOING_BUFFER_SIZE: usize = 64;
+
+// Frames are tagged by their action, so a mistyped one is rejected rather than mistaken for a
+// message.
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "camelCase")]
+enum Frame {
+    Subscribe(SubscriptionFrame),
+    Unsubscribe(SubscriptionFrame),
+    Publish(PublishFrame),
+}
+
+// Publish frames are validated like publish requests, strictly unless disabled by the server.
+fn parse_frame(value: Value, config: &Config) -> Result<Frame, Error> {
+    if config.strict_payloads && value.get("action").and_then(Value::as_str) == Some("publish") {
+        let mut publish = value.clone();
+        if let Some(frame) = publish.as_object_mut() {
+            frame.remove("action");
+            if frame.len() == 1 {
+                if let Some(brokers) = frame.remove("brokers") {
+                    publish = brokers;
+                }
+            }
+        }
+        validate_strict(&publish, config)?;
+    }
+    Frame::deserialize(value).map_err(|err| Error::JsonFormat.because(err))
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+#[allow(clippy::large_enum_variant)]
+enum PublishFrame {
+    Multiple { brokers: Vec<Broker> },
+    Single(Broker),
+    // Published using the broker specified by the headers of the upgrade request.
+    Message(Message),
+}
+
+#[derive(Deserialize)]
+struct SubscriptionFrame {
+    #[serde(
+        default,
+        alias = "broker",
+        alias = "host",
+        alias = "hostname",
+        deserialize_with = "SubscriptionFrame::deserialize_url"
+    )]
+    url: Option<Target>,
+    #[serde(flatten)]
+    credentials: Option<Credentials>,
+    #[serde(flatten)]
+    tls: TlsOptions,
+    #[serde(flatten)]
+    options: ClientOptions,
+    topic: String,
+    #[serde(
+        default = "Message::default_qos",
+        deserialize_with = "Message::deserialize_qos"
+    )]
+    qos: i32,
+    // Decodes the payloads of received messages.
+    layout: Option<String>,
+}
+
+impl SubscriptionFrame {
+    fn deserialize_url<'de, D>(deserializer: D) -> Result<Option<Target>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Broker::deserialize_url(deserializer).map(Some)
+    }
+
+    // Fills the connection information from the upgrade request if the frame doesn't specify any.
+    fn connect_info(
+        self,
+        default: Option<&ConnectInfo>,
+        config: &Config,
+    ) -> Result<(ConnectInfo, String), Error> {
+        let connect_info = match self.url {
+            Some(target) => {
+                ConnectInfo::resolve(target, self.credentials, self.tls, self.options, config)?
+            }
+            None => default.cloned().ok_or(Error::BrokerUrl)?,
+        };
+        Ok((connect_info, self.topic))
+    }
+}
+
+#[derive(Serialize, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Outgoing {
+    Message(ReceivedMessage),
+    Published,
+    Subscribed { topic: String },
+    Unsubscribed { topic: String },
+    // Messages dropped as the connection didn't keep up with the subscription.
+    Lost { topic: String, count: u64 },
+    Error { error: String },
+}
+
+pub fn upgrade(
+    websocket: WebSocketUpgrade,
+    default: Option<ConnectInfo>,
+    identity: Identity,
+    state: AppState,
+) -> Response {
+    websocket.on_upgrade(move |socket| {
+        let (outgoing, receiver) = mpsc::channel(OUTGOING_BUFFER_SIZE);
+        Session {
+            default,
+            identity,
+            state,
+            outgoing,
+            subscriptions: HashMap::new(),
+        }
+        .run(socket, receiver)
+    })
+}
+
+struct Session {
+    default: Option<ConnectInfo>,
+    // Authorizes every frame, as topics and brokers are only known once received.
+    identity: Identity,
+    state: AppState,
+    outgoing: mpsc::Sender<Outgoing>,
+    subscriptions: HashMap<(Url, String), Forwarder>,
+}
+
+// Forwards the messages of a subscription to the session, remembering the parameters it was
+// subscribed with.
+struct Forwarder {
+    connect_info: ConnectInfo,
+    qos: i32,
+    layout: Option<String>,
+    task: JoinHandle<()>,
+}
+
+impl Forwarder {
+    // Subscribing again is only acknowledged when nothing would change, as the subscription would
+    // otherwise keep its parameters.
+    fn resubscribe(
+        &self,
+        connect_info: &ConnectInfo,
+        qos: i32,
+        layout: Option<&str>,
+    ) -> Result<(), Error> {
+        if self.connect_info != *connect_info || self.qos != qos || self.layout.as_deref() != layout
+        {
+            return Err(Error::SubscribeOptions
+                .because("already subscribed with other connection options, QoS or layout"));
+        }
+        Ok(())
+    }
+}
+
+impl Session {
+    async fn run(mut self, socket: WebSocket, mut receiver: mpsc::Receiver<Outgoing>) {
+        let (mut sink, mut stream) = socket.split();
+        let writer = tokio::spawn(async move {
+            while let Some(frame) = receiver.recv().await {
+                let text = match serde_json::to_string(&frame) {
+                    Ok(text) => text,
+                    Err(_) => continue,
+                };
+                if sink.send(WsMessage::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        while let Some(Ok(frame)) = stream.next().await {
+            let frame = match frame {
+                WsMessage::Text(text) => serde_json::from_str(&text),
+                WsMessage::Binary(data) => serde_json::from_slice(&data),
+                WsMessage::Close(_) => break,
+                WsMessage::Ping(_) | WsMessage::Pong(_) => continue,
+            };
+            let reply = match frame
+                .map_err(|err| Error::JsonFormat.because(err))
+                .and_then(|frame| parse_frame(frame, &self.state.config))
+            {
+                Ok(frame) => self.handle(frame).await,
+                Err(err) => Err(err),
+            };
+            let reply = reply.unwrap_or_else(|err| Outgoing::Error {
+                error: err.to_string(),
+            });
+            if self.outgoing.send(reply).await.is_err() {
+                break;
+            }
+        }
+
+        for (_, forwarder) in self.subscriptions.drain() {
+            forwarder.task.abort();
+        }
+        writer.abort();
+    }
+
+    async fn handle(&mut self, frame: Frame) -> Result<Outgoing, Error> {
+        match frame {
+            Frame::Subscribe(mut frame) => {
+                let qos = frame.qos;
+                let layout_name = frame.layout.take();
+                let layout = layout_name
+                    .as_deref()
+                    .map(|layout| {
+                        self.state
+                            .config
+                            .layouts
+                            .resolve(layout, self.state.config.max_body_size)
+                            .map_err(|err| err.field("layout"))
+                    })
+                    .transpose()?;
+                let (connect_info, topic) =
+                    frame.connect_info(self.default.as_ref(), &self.state.config)?;
+                self.subscribe(connect_info, topic, qos, layout_name, layout)
+                    .await
+            }
+            Frame::Unsubscribe(frame) => {
+                let (connect_info, topic) =
+                    frame.connect_info(self.default.as_ref(), &self.state.config)?;
+                let forwarder = self
+                    .subscriptions
+                    .remove(&(connect_info.broker, topic.clone()))
+                    .ok_or(Error::Topic)?;
+                forwarder.task.abort();
+                Ok(Outgoing::Unsubscribed { topic })
+            }
+            // WebSocket sessions are upgraded from the subscribe route, which stays enabled.
+            Frame::Publish(_) if !self.state.config.enable_publish => Err(Error::PublishDisabled),
+            Frame::Publish(frame) => {
+                let mut req = match frame {
+                    PublishFrame::Multiple { brokers } => PublishRequest::Multiple(brokers),
+                    PublishFrame::Single(broker) => PublishRequest::Single(broker),
+                    PublishFrame::Message(message) => {
+                        let connect_info = self.default.clone().ok_or(Error::BrokerUrl)?;
+                        PublishRequest::Single(Broker::new(
+                            connect_info,
+                            MessageGroup::Flat(message),
+                        ))
+                    }
+                };
+                req.pack_structs(&self.state.config)?;
+                req.authorize(&self.identity, &self.state.config).await?;
+                let options = PublishOptions::new(&self.state.config);
+                let report = req
+                    .publish(&self.state.pool, &self.state.config, &options)
+                    .await;
+                for broker in report.brokers {
+                    broker.into_result()?;
+                }
+                Ok(Outgoing::Published)
+            }
+        }
+    }
+
+    async fn subscribe(
+        &mut self,
+        connect_info: ConnectInfo,
+        topic: String,
+        qos: i32,
+        layout_name: Option<String>,
+        layout: Option<Layout>,
+    ) -> Result<Outgoing, Error> {
+        let key = (connect_info.broker.clone(), topic.clone());
+        if let Some(forwarder) = self.subscriptions.get(&key) {
+            forwarder.resubscribe(&connect_info, qos, layout_name.as_deref())?;
+            return Ok(Outgoing::Subscribed { topic });
+        }
+        self.state
+            .config
+            .acl
+            .authorize(
+                &self.identity,
+                &connect_info.broker,
+                [topic.as_str()],
+                Access::Subscribe,
+            )
+            .await?;
+
+        let mut subscriber = self
+            .state
+            .hub
+            .subscribe(
+                connect_info.clone(),
+                topic.clone(),
+                qos,
+                None,
+                &self.state.config,
+            )
+            .await?;
+        let outgoing = self.outgoing.clone();
+        let forwarded_topic = topic.clone();
+        let task = tokio::spawn(async move {
+            while let Some(message) = subscriber.recv().await {
+                let count = subscriber.take_lost();
+                if count > 0 {
+                    let topic = forwarded_topic.clone();
+                    if outgoing
+                        .send(Outgoing::Lost { topic, count })
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                let frame = Outgoing::Message(ReceivedMessage::new(&message, layout.as_ref()));
+                if outgoing.send(frame).await.is_err() {
+                    return;
+                }
+            }
+            let _ = outgoing
+                .send(Outgoing::Error {
+                    error: format!("subscription to {} lost", forwarded_topic),
+                })
+                .await;
+        });
+        self.subscriptions.insert(
+            key,
+            Forwarder {
+                connect_info,
+                qos,
+                layout: layout_name,
+                task,
+            },
+        );
+        Ok(Outgoing::Subscribed { topic })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::{parse_frame, Forwarder, Frame, Outgoing, PublishFrame};
+    use crate::{
+        config::Config,
+        connect_info::{ConnectInfo, Credentials},
+        subscribe::{ReceivedMessage, ReceivedPayload},
+        Error,
+    };
+
+    fn frame(json: Value) -> Frame {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn publish_frame(json: Value) -> PublishFrame {
+        match frame(json) {
+            Frame::Publish(frame) => frame,
+            _ => panic!("expected a publish frame"),
+        }
+    }
+
+    #[test]
+    fn subscribe_frame() {
+        match frame(json!({
+            "action": "subscribe",
+            "broker": "broker.com",
+            "topic": "sensors/#",
+        })) {
+            Frame::Subscribe(frame) => {
+                assert_eq!(frame.url, Some("tcp://broker.com".parse().unwrap()));
+                assert_eq!(frame.topic, "sensors/#");
+            }
+            _ => panic!("expected a subscribe frame"),
+        }
+    }
+
+    #[test]
+    fn unsubscribe_frame_default_broker() {
+        match frame(json!({
+            "action": "unsubscribe",
+            "topic": "door",
+        })) {
+            Frame::Unsubscribe(frame) => assert_eq!(frame.url, None),
+            _ => panic!("expected an unsubscribe frame"),
+        }
+    }
+
+    #[test]
+    fn broker_frames() {
+        assert!(matches!(
+            publish_frame(json!({
+                "action": "publish",
+                "broker": "broker.com",
+                "topic": "door",
+                "payload": "open",
+            })),
+            PublishFrame::Single(_)
+        ));
+        assert!(matches!(
+            publish_frame(json!({
+                "action": "publish",
+                "brokers": [
+                    {"broker": "broker.com", "topic": "door", "payload": "open"},
+                    {"broker": "other.com", "topic": "door", "payload": "open"},
+                ],
+            })),
+            PublishFrame::Multiple { brokers } if brokers.len() == 2
+        ));
+    }
+
+    #[test]
+    fn message_frame() {
+        assert!(matches!(
+            publish_frame(json!({
+                "action": "publish",
+                "topic": "door",
+                "payload": "open",
+            })),
+            PublishFrame::Message(_)
+        ));
+    }
+
+    #[test]
+    fn strict_frames() {
+        let strict = Config::default();
+        let lenient = Config {
+            strict_payloads: false,
+            ..Default::default()
+        };
+        let strict_err = |json: Value| {
+            assert!(parse_frame(json.clone(), &lenient).is_ok());
+            parse_frame(json, &strict).err().unwrap()
+        };
+        let err = strict_err(
+            json!({"action": "publish", "topic": "door", "payloadtype": "hex", "payload": "00"}),
+        );
+        assert!(matches!(err.kind(), Error::JsonFormat));
+        let err = strict_err(
+            json!({"action": "publish", "topic": "door", "payloadType": "bin", "payload": "00"}),
+        );
+        assert!(matches!(err.kind(), Error::PayloadType));
+        let err = strict_err(json!({
+            "action": "publish",
+            "brokers": [{"broker": "broker.com", "topic": "door", "qos": 1, "retian": true}],
+        }));
+        assert!(matches!(err.kind(), Error::JsonFormat));
+
+        assert!(parse_frame(
+            json!({"action": "publish", "topic": "door", "payloadType": "hex", "payload": "00"}),
+            &strict
+        )
+        .is_ok());
+        assert!(matches!(
+            parse_frame(json!({"action": "subscribe", "topic": "door"}), &strict),
+            Ok(Frame::Subscribe(_))
+        ));
+        assert!(matches!(
+            parse_frame(json!({"action": "publish"}), &strict)
+                .as_ref()
+                .map_err(Error::kind),
+            Err(Error::JsonFormat)
+        ));
+    }
+
+    #[tokio::test]
+    async fn resubscribe() {
+        let connect_info = ConnectInfo {
+            broker: "tcp://broker.com:1883".parse().unwrap(),
+            credentials: None,
+            tls: Default::default(),
+            options: Default::default(),
+        };
+        let forwarder = Forwarder {
+            connect_info: connect_info.clone(),
+            qos: 1,
+            layout: None,
+            task: tokio::spawn(async {}),
+        };
+        assert!(forwarder.resubscribe(&connect_info, 1, None).is_ok());
+        let credentials = ConnectInfo {
+            credentials: Some(Credentials {
+                username: "user".to_owned(),
+                password: "pass".to_owned(),
+            }),
+            ..connect_info.clone()
+        };
+        for (connect_info, qos, layout) in [
+            (&credentials, 1, None),
+            (&connect_info, 2, None),
+            (&connect_info, 1, Some("u8 state")),
+        ] {
+            assert!(matches!(
+                forwarder
+                    .resubscribe(connect_info, qos, layout)
+                    .as_ref()
+                    .map_err(Error::kind),
+                Err(Error::SubscribeOptions)
+            ));
+        }
+    }
+
+    #[test]
+    fn unknown_action() {
+        for json in [
+            json!({"action": "subscibe", "topic": "door"}),
+            json!({"topic": "door", "payload": "open"}),
+        ] {
+            assert!(serde_json::from_value::<Frame>(json).is_err());
+        }
+    }
+
+    #[test]
+    fn outgoing_message() {
+        assert_eq!(
+            serde_json::to_value(Outgoing::Message(ReceivedMessage {
+                topic: "door".to_owned(),
+                qos: 1,
+                retain: false,
+                payload: ReceivedPayload::String("open".to_owned()),
+                properties: Default::default(),
+            }))
+            .unwrap(),
+            json!({
+                "type": "message",
+                "topic": "door",
+                "qos": 1,
+                "retain": false,
+                "payloadType": "string",
+                "payload": "open",
+            })
+        );
+    }
+}